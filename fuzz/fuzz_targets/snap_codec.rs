@@ -0,0 +1,36 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use time::Instant;
+use tokio_util::codec::{Decoder, Encoder};
+
+// The main crate is a binary, so pull in the codec directly
+#[path = "../../src/controller/music/snap/protocol.rs"]
+#[allow(dead_code)]
+mod protocol;
+
+use protocol::SnapCodec;
+
+fuzz_target!(|data: &[u8]| {
+    let mut codec = SnapCodec::new(Instant::now());
+    let mut src = BytesMut::from(data);
+
+    // Whatever the input, decoding has to return an error rather than panic
+    while let Ok(Some(msg)) = codec.decode(&mut src) {
+        let kind = msg.kind.clone();
+
+        // Anything we decode should come back the same after encoding it again.
+        // Encoding can fail for values that don't fit the wire format, that's fine.
+        let mut encoded = BytesMut::new();
+        if codec.encode(msg, &mut encoded).is_ok() {
+            let decoded = codec
+                .decode(&mut encoded)
+                .expect("Error decoding an encoded message")
+                .expect("Encoded message is incomplete");
+
+            assert_eq!(kind, decoded.kind);
+            assert!(encoded.is_empty());
+        }
+    }
+});
@@ -0,0 +1,234 @@
+use anyhow::{anyhow, Result};
+use chrono::{Local, NaiveTime, Timelike};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::color::{Color, NUM_LIGHTS};
+
+/// How often we check the clock to see if it's night
+const NIGHT_CHECK_PERIOD: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrightnessConfig {
+    /// The brightness we start with, between 0 and 1
+    pub master: f64,
+    pub night: NightConfig,
+}
+
+impl Default for BrightnessConfig {
+    fn default() -> Self {
+        BrightnessConfig {
+            master: 1.0,
+            night: NightConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NightConfig {
+    /// The brightest the lights can be at night, between 0 and 1
+    pub cap: f64,
+    /// When night starts in local time, like "23:00"
+    #[serde(deserialize_with = "deserialize_time_of_day")]
+    pub start: u32,
+    /// When night ends in local time, like "07:00"
+    #[serde(deserialize_with = "deserialize_time_of_day")]
+    pub end: u32,
+    /// What the night mode starts out as
+    pub mode: NightMode,
+}
+
+impl Default for NightConfig {
+    fn default() -> Self {
+        NightConfig {
+            cap: 0.3,
+            start: 23 * 60,
+            end: 7 * 60,
+            mode: NightMode::Auto,
+        }
+    }
+}
+
+/// Times of day are written as "HH:MM" and stored as minutes since midnight
+fn deserialize_time_of_day<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+    let s: String = Deserialize::deserialize(d)?;
+
+    parse_time_of_day(&s).map_err(serde::de::Error::custom)
+}
+
+fn parse_time_of_day(s: &str) -> Result<u32> {
+    let mut parts = s.splitn(2, ':');
+
+    let hour: u32 = parts.next().unwrap_or("").trim().parse()?;
+    let minute: u32 = parts.next().unwrap_or("0").trim().parse()?;
+
+    if hour > 23 || minute > 59 {
+        return Err(anyhow!("{} is not a valid time of day", s));
+    }
+
+    Ok(hour * 60 + minute)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NightMode {
+    /// Follow the schedule
+    Auto,
+    On,
+    Off,
+}
+
+/// The brightness applied on top of whichever controller is active
+#[derive(Debug)]
+pub struct Brightness {
+    master: f64,
+    mode: NightMode,
+    night: NightConfig,
+
+    /// Whether the schedule says it's night, checked every so often
+    is_night: bool,
+    last_check: Option<Instant>,
+}
+
+impl Brightness {
+    pub fn new(config: BrightnessConfig) -> Brightness {
+        Brightness {
+            master: config.master.clamp(0.0, 1.0),
+            mode: config.night.mode,
+            night: config.night,
+
+            is_night: false,
+            last_check: None,
+        }
+    }
+
+    pub fn master(&self) -> f64 {
+        self.master
+    }
+
+    pub fn set_master(&mut self, master: f64) {
+        self.master = master.clamp(0.0, 1.0);
+    }
+
+    pub fn mode(&self) -> NightMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: NightMode) {
+        self.mode = mode;
+    }
+
+    /// Whether the night cap is currently being applied
+    pub fn is_night(&mut self) -> bool {
+        match self.mode {
+            NightMode::On => true,
+            NightMode::Off => false,
+            NightMode::Auto => {
+                if self.last_check.is_none_or(|last| last.elapsed() > NIGHT_CHECK_PERIOD) {
+                    self.last_check = Some(Instant::now());
+                    // The time crate can't get the local time once there's more than one thread, chrono can
+                    self.is_night = self.check_schedule(Local::now().time());
+                }
+
+                self.is_night
+            }
+        }
+    }
+
+    /// Whether `now` (in local time) is during the night
+    fn check_schedule(&self, now: NaiveTime) -> bool {
+        let minutes = now.hour() * 60 + now.minute();
+
+        if self.night.start <= self.night.end {
+            self.night.start <= minutes && minutes < self.night.end
+        } else {
+            // Night goes past midnight
+            minutes >= self.night.start || minutes < self.night.end
+        }
+    }
+
+    /// The factor every light's intensity is multiplied by
+    pub fn factor(&mut self) -> f64 {
+        if self.is_night() {
+            self.master.min(self.night.cap)
+        } else {
+            self.master
+        }
+    }
+
+    pub fn apply(&mut self, mut colors: [Color; NUM_LIGHTS]) -> [Color; NUM_LIGHTS] {
+        let factor = self.factor();
+
+        for color in colors.iter_mut() {
+            color.i = (color.i as f64 * factor).round() as u8;
+        }
+
+        colors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brightness(start: &str, end: &str) -> Brightness {
+        let config = BrightnessConfig {
+            night: NightConfig {
+                start: parse_time_of_day(start).unwrap(),
+                end: parse_time_of_day(end).unwrap(),
+                ..NightConfig::default()
+            },
+            ..BrightnessConfig::default()
+        };
+
+        Brightness::new(config)
+    }
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn schedule_within_a_day() {
+        let b = brightness("01:30", "06:00");
+
+        assert!(!b.check_schedule(at(1, 29)));
+        assert!(b.check_schedule(at(1, 30)));
+        assert!(b.check_schedule(at(5, 59)));
+        assert!(!b.check_schedule(at(6, 0)));
+        assert!(!b.check_schedule(at(23, 0)));
+    }
+
+    #[test]
+    fn schedule_past_midnight() {
+        let b = brightness("23:00", "07:00");
+
+        assert!(!b.check_schedule(at(22, 59)));
+        assert!(b.check_schedule(at(23, 0)));
+        assert!(b.check_schedule(at(0, 0)));
+        assert!(b.check_schedule(at(6, 59)));
+        assert!(!b.check_schedule(at(7, 0)));
+        assert!(!b.check_schedule(at(12, 0)));
+    }
+
+    #[test]
+    fn night_mode_caps_the_factor() {
+        let mut b = brightness("23:00", "07:00");
+
+        b.set_mode(NightMode::On);
+        assert_eq!(b.factor(), 0.3);
+
+        b.set_mode(NightMode::Off);
+        assert_eq!(b.factor(), 1.0);
+    }
+
+    #[test]
+    fn time_of_day() {
+        assert_eq!(parse_time_of_day("07:05").unwrap(), 7 * 60 + 5);
+        assert_eq!(parse_time_of_day("23").unwrap(), 23 * 60);
+        assert!(parse_time_of_day("24:00").is_err());
+        assert!(parse_time_of_day("12:60").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use serde::Deserialize;
+
+use crate::color::Color;
+
+/// Known starting points for calibrating an output
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Profile {
+    /// Monitors already apply their own gamma, so leave colors alone
+    Display,
+    Ws2811,
+    Ws2812,
+    Sk6812,
+}
+
+impl Profile {
+    fn gamma(self) -> f64 {
+        match self {
+            Profile::Display => 1.0,
+            Profile::Ws2811 | Profile::Ws2812 | Profile::Sk6812 => 2.6,
+        }
+    }
+
+    /// The scale applied to red, green and blue to make white look white
+    fn white_point(self) -> [f64; 3] {
+        match self {
+            Profile::Display => [1.0, 1.0, 1.0],
+            // These strips have quite a strong blue tint
+            Profile::Ws2811 | Profile::Ws2812 => [1.0, 0.85, 0.7],
+            Profile::Sk6812 => [1.0, 0.9, 0.8],
+        }
+    }
+
+    /// The smallest channel value that actually lights up an LED
+    fn min_visible(self) -> u8 {
+        match self {
+            Profile::Display => 0,
+            Profile::Ws2811 | Profile::Ws2812 | Profile::Sk6812 => 1,
+        }
+    }
+}
+
+/// Adjustments on top of a profile, anything left unset comes from the profile
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalibrationConfig {
+    pub profile: Option<Profile>,
+    pub gamma: Option<f64>,
+    pub white_point: Option<[f64; 3]>,
+    pub min_visible: Option<u8>,
+}
+
+impl CalibrationConfig {
+    /// Build the calibration, using `default` if no profile was configured
+    pub fn build(&self, default: Profile) -> Calibration {
+        let profile = self.profile.unwrap_or(default);
+
+        Calibration::new(
+            self.gamma.unwrap_or_else(|| profile.gamma()),
+            self.white_point.unwrap_or_else(|| profile.white_point()),
+            self.min_visible.unwrap_or_else(|| profile.min_visible()),
+        )
+    }
+}
+
+/// Converts the sRGB colors that controllers produce into the values an output should display
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    /// A lookup table for each of red, green and blue
+    tables: [[u8; 256]; 3],
+}
+
+impl Calibration {
+    pub fn new(gamma: f64, white_point: [f64; 3], min_visible: u8) -> Calibration {
+        let mut tables = [[0; 256]; 3];
+
+        for (table, scale) in tables.iter_mut().zip(white_point.iter()) {
+            for (n, entry) in table.iter_mut().enumerate() {
+                let val = (n as f64 / 255.0).powf(gamma) * scale.clamp(0.0, 1.0) * 255.0;
+                let val = val.round() as u8;
+
+                // Don't let dim colors disappear entirely
+                *entry = if n > 0 { val.max(min_visible) } else { 0 };
+            }
+        }
+
+        Calibration { tables }
+    }
+
+    pub fn apply(&self, color: Color) -> Color {
+        Color {
+            i: color.i,
+            r: self.tables[0][color.r as usize],
+            g: self.tables[1][color.g as usize],
+            b: self.tables[2][color.b as usize],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(color: Color) -> (u8, u8, u8) {
+        (color.r, color.g, color.b)
+    }
+
+    fn grey(val: u8) -> Color {
+        Color { i: u8::MAX, r: val, g: val, b: val }
+    }
+
+    #[test]
+    fn display_leaves_colors_alone() {
+        let calibration = CalibrationConfig::default().build(Profile::Display);
+
+        for val in 0..=u8::MAX {
+            assert_eq!(rgb(calibration.apply(grey(val))), (val, val, val));
+        }
+    }
+
+    #[test]
+    fn strip_profile() {
+        let calibration = CalibrationConfig::default().build(Profile::Ws2812);
+
+        // Black stays off, white is tinted by the white point
+        assert_eq!(rgb(calibration.apply(grey(0))), (0, 0, 0));
+        assert_eq!(rgb(calibration.apply(grey(255))), (255, 217, 179));
+
+        // Gamma pulls the middle down, but the dimmest color still lights up
+        assert_eq!(calibration.apply(grey(128)).r, 42);
+        assert_eq!(rgb(calibration.apply(grey(1))), (1, 1, 1));
+
+        // Brighter inputs are never dimmer
+        for val in 1..=u8::MAX {
+            assert!(calibration.apply(grey(val)).r >= calibration.apply(grey(val - 1)).r);
+        }
+    }
+
+    #[test]
+    fn config_overrides_profile() {
+        let config = CalibrationConfig {
+            profile: Some(Profile::Ws2812),
+            gamma: Some(1.0),
+            white_point: None,
+            min_visible: Some(0),
+        };
+        let calibration = config.build(Profile::Display);
+
+        assert_eq!(rgb(calibration.apply(grey(128))), (128, 109, 90));
+        assert_eq!(calibration.apply(Color { i: 7, r: 0, g: 0, b: 0 }).i, 7);
+    }
+}
@@ -1,6 +1,243 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::color::Color;
+use crate::color::space::{Blend, Srgb};
+
 mod magma;
 mod inferno;
+mod viridis;
+mod plasma;
+mod rainbow;
 
 // TODO scarlet seems to be causing us to be linked against libc
 pub use magma::MAGMA_DATA;
-pub use inferno::INFERNO_DATA;
\ No newline at end of file
+pub use inferno::INFERNO_DATA;
+pub use viridis::VIRIDIS_DATA;
+pub use plasma::PLASMA_DATA;
+pub use rainbow::RAINBOW_DATA;
+
+/// The number of entries in every colormap, one for each possible value of a light
+const COLORMAP_SIZE: usize = 256;
+
+/// Maps a light's value onto a color
+#[derive(Debug, Clone)]
+pub struct Colormap {
+    data: Vec<[f64; 3]>,
+}
+
+impl Colormap {
+    pub fn from_data(data: &[[f64; 3]; COLORMAP_SIZE]) -> Colormap {
+        Colormap { data: data.to_vec() }
+    }
+
+    /// Build a colormap by interpolating between stops in the `blend` color space.
+    /// Every stop needs its own position, there's nothing to interpolate between two at the same place.
+    pub fn from_stops(stops: &[Stop], blend: Blend) -> Result<Colormap> {
+        if let Some(stop) = stops.iter().find(|stop| !stop.position.is_finite()) {
+            bail!("Colormap stop position {} isn't a number", stop.position);
+        }
+
+        let mut stops = stops.to_vec();
+        stops.sort_by(|a, b| a.position.partial_cmp(&b.position).unwrap_or(std::cmp::Ordering::Equal));
+
+        if let Some(w) = stops.windows(2).find(|w| w[0].position == w[1].position) {
+            bail!("More than one colormap stop is at position {}", w[0].position);
+        }
+
+        let (first, last) = match (stops.first(), stops.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => bail!("A colormap needs at least one stop"),
+        };
+
+        let data = (0..COLORMAP_SIZE)
+            .map(|n| {
+                let position = n as f64 / (COLORMAP_SIZE - 1) as f64;
+
+                // Positions outside of the stops take the color of the closest stop
+                if position <= first.position {
+                    return first.color;
+                }
+
+                match stops.windows(2).find(|w| position <= w[1].position) {
+                    Some(w) => {
+                        let t = (position - w[0].position) / (w[1].position - w[0].position);
+                        let [r, g, b] = w[0].color;
+                        let start = Srgb::new(r, g, b);
+                        let [r, g, b] = w[1].color;
+                        let end = Srgb::new(r, g, b);
+
+                        let mixed = blend.mix(start, end, t);
+                        [mixed.r, mixed.g, mixed.b]
+                    }
+                    None => last.color,
+                }
+            })
+            .collect();
+
+        Ok(Colormap { data })
+    }
+
+    /// Read a colormap from a CSV file with one `r,g,b` line (each between 0 and 1) per entry
+    pub fn from_csv(path: &Path) -> Result<Colormap> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Error reading colormap {}", path.display()))?;
+
+        let data = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .enumerate()
+            .map(|(n, line)| {
+                let values = line
+                    .split(',')
+                    .map(|v| v.trim().parse::<f64>())
+                    .collect::<Result<Vec<f64>, _>>()
+                    .with_context(|| format!("Error parsing entry {} of colormap {}", n, path.display()))?;
+
+                match values[..] {
+                    [r, g, b] => Ok([r, g, b]),
+                    _ => Err(anyhow!("Entry {} of colormap {} doesn't have 3 values", n, path.display())),
+                }
+            })
+            .collect::<Result<Vec<[f64; 3]>>>()?;
+
+        if data.len() != COLORMAP_SIZE {
+            bail!("Colormap {} has {} entries instead of {}", path.display(), data.len(), COLORMAP_SIZE);
+        }
+
+        Ok(Colormap { data })
+    }
+
+    /// The color for a value. The intensity is the value too, so quiet lights are dim as well as dark.
+    pub fn color(&self, val: u8) -> Color {
+        let mapped = self.data[val as usize];
+
+        Color {
+            i: val,
+            r: (mapped[0].clamp(0.0, 1.0) * 255.0) as u8,
+            g: (mapped[1].clamp(0.0, 1.0) * 255.0) as u8,
+            b: (mapped[2].clamp(0.0, 1.0) * 255.0) as u8,
+        }
+    }
+}
+
+/// A color at some position (between 0 and 1) of a gradient
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Stop {
+    pub position: f64,
+    /// Red, green and blue, each between 0 and 1
+    pub color: [f64; 3],
+}
+
+/// The matplotlib colormaps and a rainbow. Any other published table, like turbo or cividis, can be
+/// loaded from a CSV file.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Builtin {
+    Inferno,
+    Magma,
+    Viridis,
+    Plasma,
+    Rainbow,
+}
+
+impl Builtin {
+    fn data(self) -> &'static [[f64; 3]; COLORMAP_SIZE] {
+        match self {
+            Builtin::Inferno => &INFERNO_DATA,
+            Builtin::Magma => &MAGMA_DATA,
+            Builtin::Viridis => &VIRIDIS_DATA,
+            Builtin::Plasma => &PLASMA_DATA,
+            Builtin::Rainbow => &RAINBOW_DATA,
+        }
+    }
+}
+
+/// How a colormap is described in the config, either by name, a list of stops or a CSV file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ColormapConfig {
+    Builtin(Builtin),
+    Stops {
+        stops: Vec<Stop>,
+        /// Oklab keeps the gradient even, the other spaces are there for the look they give
+        #[serde(default)]
+        blend: Blend,
+    },
+    Csv { csv: PathBuf },
+}
+
+impl Default for ColormapConfig {
+    fn default() -> Self {
+        ColormapConfig::Builtin(Builtin::Inferno)
+    }
+}
+
+impl ColormapConfig {
+    pub fn load(&self) -> Result<Colormap> {
+        match self {
+            ColormapConfig::Builtin(builtin) => Ok(Colormap::from_data(builtin.data())),
+            ColormapConfig::Stops { stops, blend } => Colormap::from_stops(stops, *blend),
+            ColormapConfig::Csv { csv } => Colormap::from_csv(csv),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(position: f64, color: [f64; 3]) -> Stop {
+        Stop { position, color }
+    }
+
+    #[test]
+    fn stops_interpolate() {
+        let stops = [stop(1.0, [1.0, 1.0, 1.0]), stop(0.0, [0.0, 0.0, 0.0])];
+        let colormap = Colormap::from_stops(&stops, Blend::Oklab).unwrap();
+
+        let black = colormap.color(0);
+        assert_eq!((black.r, black.g, black.b), (0, 0, 0));
+
+        let white = colormap.color(255);
+        assert!(white.r >= 254 && white.g >= 254 && white.b >= 254);
+
+        // Grey in between
+        let mid = colormap.color(128);
+        assert!(mid.r > 0 && mid.r < 254);
+        assert!(mid.r.abs_diff(mid.g) <= 1 && mid.g.abs_diff(mid.b) <= 1);
+    }
+
+    #[test]
+    fn intensity_follows_the_value() {
+        let colormap = ColormapConfig::default().load().unwrap();
+
+        for val in [0, 1, 128, 255] {
+            let color = colormap.color(val);
+            let [r, g, b] = INFERNO_DATA[val as usize];
+
+            assert_eq!(color.i, val);
+            assert_eq!((color.r, color.g, color.b), ((r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8));
+        }
+    }
+
+    #[test]
+    fn stops_at_the_same_position() {
+        let stops = [stop(0.0, [0.0, 0.0, 0.0]), stop(0.5, [1.0, 0.0, 0.0]), stop(0.5, [0.0, 0.0, 1.0])];
+
+        assert!(Colormap::from_stops(&stops, Blend::Oklab).is_err());
+    }
+
+    #[test]
+    fn stop_position_is_nan() {
+        assert!(Colormap::from_stops(&[stop(0.0, [0.0; 3]), stop(f64::NAN, [1.0; 3])], Blend::Oklab).is_err());
+    }
+
+    #[test]
+    fn no_stops() {
+        assert!(Colormap::from_stops(&[], Blend::Oklab).is_err());
+    }
+}
@@ -1,7 +1,18 @@
+pub mod calibration;
 pub mod cmap;
+pub mod palette;
+pub mod power;
+pub mod rgbw;
+pub mod space;
+
+use space::{LinearRgb, Srgb};
 
 pub const NUM_LIGHTS: usize = 3;
 
+/// The color of a single light.
+///
+/// `r`, `g` and `b` are sRGB values. `i` is the intensity, it scales the amount of light
+/// the color puts out, so 128 is half as much light as 255 and 0 is off no matter the color.
 #[derive(Debug, Copy, Clone)]
 pub struct Color {
     pub i: u8,
@@ -10,8 +21,54 @@ pub struct Color {
     pub b: u8,
 }
 
+impl Color {
+    /// Fold the intensity into the color, so it can be displayed at full intensity
+    pub fn apply_intensity(self) -> Color {
+        if self.i == u8::MAX {
+            return self;
+        }
+
+        let scale = self.i as f64 / 255.0;
+        let linear = LinearRgb::from(Srgb::from(self));
+
+        Srgb::from(LinearRgb::new(linear.r * scale, linear.g * scale, linear.b * scale)).to_color(u8::MAX)
+    }
+}
+
 pub const OFF: [Color; NUM_LIGHTS] = [
     Color { i: 0, r: 0, g: 0, b: 0},
     Color { i: 0, r: 0, g: 0, b: 0},
     Color { i: 0, r: 0, g: 0, b: 0},
-];
\ No newline at end of file
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_intensity_is_unchanged() {
+        let color = Color { i: u8::MAX, r: 10, g: 200, b: 99 }.apply_intensity();
+
+        assert_eq!((color.i, color.r, color.g, color.b), (u8::MAX, 10, 200, 99));
+    }
+
+    #[test]
+    fn zero_intensity_is_off() {
+        let color = Color { i: 0, r: 255, g: 255, b: 255 }.apply_intensity();
+
+        assert_eq!((color.i, color.r, color.g, color.b), (u8::MAX, 0, 0, 0));
+    }
+
+    #[test]
+    fn intensity_scales_light() {
+        // Half the light of white, which is much more than half the sRGB value
+        let color = Color { i: 128, r: 255, g: 255, b: 0 }.apply_intensity();
+        let linear = LinearRgb::from(Srgb::from(color));
+
+        assert_eq!(color.i, u8::MAX);
+        assert_eq!(color.r, color.g);
+        assert_eq!(color.b, 0);
+        assert!(color.r > 128);
+        assert!((linear.r - 128.0 / 255.0).abs() < 0.01, "{}", linear.r);
+    }
+}
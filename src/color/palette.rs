@@ -0,0 +1,85 @@
+//! Picks the main colors out of an image, so the lights can match the album art.
+
+use image::DynamicImage;
+
+use crate::color::cmap::{Colormap, Stop};
+use crate::color::space::{Blend, Oklab, Srgb};
+
+/// Images are shrunk to this size before looking at the pixels, which is plenty for a palette
+const THUMBNAIL_SIZE: u32 = 64;
+/// The number of k-means rounds, the clusters barely move after this
+const ROUNDS: usize = 10;
+
+/// Find the `size` most prominent colors of an image, from darkest to lightest
+pub fn from_image(image: &DynamicImage, size: usize) -> Vec<Srgb> {
+    let pixels: Vec<Oklab> = image
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .to_rgb8()
+        .pixels()
+        .map(|p| Srgb::new(p[0] as f64 / 255.0, p[1] as f64 / 255.0, p[2] as f64 / 255.0).into())
+        .collect();
+
+    let mut palette: Vec<Srgb> = k_means(&pixels, size).into_iter().map(Srgb::from).collect();
+    palette.iter_mut().for_each(|c| *c = c.clamp());
+
+    palette
+}
+
+/// Cluster the colors in Oklab, so the clusters are colors that look alike
+fn k_means(pixels: &[Oklab], k: usize) -> Vec<Oklab> {
+    if pixels.is_empty() || k == 0 {
+        return Vec::new();
+    }
+
+    // Start with colors spread out from dark to light, which is also how the result is sorted
+    let mut sorted = pixels.to_vec();
+    sorted.sort_by(|a, b| a.l.partial_cmp(&b.l).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut centers: Vec<Oklab> = (0..k).map(|n| sorted[(2 * n + 1) * sorted.len() / (2 * k)]).collect();
+
+    for _ in 0..ROUNDS {
+        let mut sums = vec![(Oklab { l: 0.0, a: 0.0, b: 0.0 }, 0usize); k];
+
+        for p in pixels {
+            let (nearest, _) = centers
+                .iter()
+                .map(|c| (c.l - p.l).powi(2) + (c.a - p.a).powi(2) + (c.b - p.b).powi(2))
+                .enumerate()
+                .fold((0, f64::MAX), |best, (n, d)| if d < best.1 { (n, d) } else { best });
+
+            let (sum, count) = &mut sums[nearest];
+            sum.l += p.l;
+            sum.a += p.a;
+            sum.b += p.b;
+            *count += 1;
+        }
+
+        for (center, (sum, count)) in centers.iter_mut().zip(sums) {
+            // Clusters that lost all their pixels stay where they are
+            if count > 0 {
+                let n = count as f64;
+                *center = Oklab { l: sum.l / n, a: sum.a / n, b: sum.b / n };
+            }
+        }
+    }
+
+    centers.sort_by(|a, b| a.l.partial_cmp(&b.l).unwrap_or(std::cmp::Ordering::Equal));
+    centers.dedup();
+
+    centers
+}
+
+impl Colormap {
+    /// A colormap going from black through each color of a palette in order
+    pub fn from_palette(palette: &[Srgb]) -> Colormap {
+        let mut stops = vec![Stop { position: 0.0, color: [0.0, 0.0, 0.0] }];
+
+        stops.extend(palette.iter().enumerate().map(|(n, c)| Stop {
+            position: (n + 1) as f64 / palette.len() as f64,
+            color: [c.r, c.g, c.b],
+        }));
+
+        // There's always at least the black stop
+        Colormap::from_stops(&stops, Blend::Oklab).unwrap()
+    }
+}
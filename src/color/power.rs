@@ -0,0 +1,163 @@
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+use crate::color::rgbw::Rgbw;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerConfig {
+    /// The most current the power supply can give the LEDs
+    pub budget_ma: f64,
+    /// The current drawn by a single red, green and blue LED at full brightness
+    pub ma_per_channel: [f64; 3],
+    /// The current drawn by a single white LED at full brightness, only RGBW strips have these
+    pub white_ma: f64,
+    /// The current each LED draws even when it's off
+    pub idle_ma: f64,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        PowerConfig {
+            budget_ma: 2_000.0,
+            ma_per_channel: [20.0, 20.0, 20.0],
+            white_ma: 20.0,
+            idle_ma: 1.0,
+        }
+    }
+}
+
+/// Scales down the brightness of frames that would draw more current than our budget
+pub struct PowerLimiter {
+    config: PowerConfig,
+
+    // Stats for the current report
+    report_period: Duration,
+    report_start: Instant,
+    frames: usize,
+    limited_frames: usize,
+    peak_ma: f64,
+    lowest_scale: f64,
+}
+
+impl PowerLimiter {
+    pub fn new(config: PowerConfig) -> PowerLimiter {
+        PowerLimiter {
+            config,
+
+            report_period: Duration::from_secs(5),
+            report_start: Instant::now(),
+            frames: 0,
+            limited_frames: 0,
+            peak_ma: 0.0,
+            lowest_scale: 1.0,
+        }
+    }
+
+    /// Estimate the current drawn by `leds_per_color` LEDs showing each color
+    pub fn estimate_ma(&self, colors: &[Rgbw], leds_per_color: usize) -> f64 {
+        let [r, g, b] = self.config.ma_per_channel;
+        let w = self.config.white_ma;
+
+        let per_color: f64 = colors
+            .iter()
+            .map(|c| (c.r as f64 * r + c.g as f64 * g + c.b as f64 * b + c.w as f64 * w) / 255.0)
+            .sum();
+
+        (per_color + self.config.idle_ma * colors.len() as f64) * leds_per_color as f64
+    }
+
+    /// Dim the colors just enough to stay under the budget
+    pub fn limit(&mut self, colors: &mut [Rgbw], leds_per_color: usize) {
+        let draw = self.estimate_ma(colors, leds_per_color);
+        let idle = self.config.idle_ma * (colors.len() * leds_per_color) as f64;
+
+        self.frames += 1;
+        self.peak_ma = self.peak_ma.max(draw);
+
+        if draw > self.config.budget_ma {
+            // Dimming can't do anything about the idle current
+            let scale = ((self.config.budget_ma - idle) / (draw - idle)).clamp(0.0, 1.0);
+
+            for color in colors.iter_mut() {
+                color.r = (color.r as f64 * scale) as u8;
+                color.g = (color.g as f64 * scale) as u8;
+                color.b = (color.b as f64 * scale) as u8;
+                color.w = (color.w as f64 * scale) as u8;
+            }
+
+            self.limited_frames += 1;
+            self.lowest_scale = self.lowest_scale.min(scale);
+        }
+
+        if self.report_start.elapsed() > self.report_period {
+            log::info!(
+                "Power stats [frames limited: {}/{}, peak draw in mA: {:.0}, lowest scale: {:.3}]",
+                self.limited_frames,
+                self.frames,
+                self.peak_ma,
+                self.lowest_scale
+            );
+
+            self.report_start = Instant::now();
+            self.frames = 0;
+            self.limited_frames = 0;
+            self.peak_ma = 0.0;
+            self.lowest_scale = 1.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgbw(r: u8, g: u8, b: u8, w: u8) -> Rgbw {
+        Rgbw { r, g, b, w }
+    }
+
+    #[test]
+    fn stays_under_budget() {
+        let mut limiter = PowerLimiter::new(PowerConfig::default());
+        let frames = [
+            [rgbw(255, 255, 255, 255); 3],
+            [rgbw(255, 0, 0, 0), rgbw(0, 255, 0, 0), rgbw(0, 0, 255, 0)],
+            [rgbw(200, 100, 50, 25), rgbw(1, 2, 3, 4), rgbw(255, 255, 255, 0)],
+        ];
+
+        for frame in frames.iter() {
+            for &leds_per_color in &[1, 10, 30, 100] {
+                let mut colors = *frame;
+                limiter.limit(&mut colors, leds_per_color);
+
+                let draw = limiter.estimate_ma(&colors, leds_per_color);
+                assert!(draw <= limiter.config.budget_ma, "{} mA with {} LEDs", draw, leds_per_color);
+            }
+        }
+    }
+
+    #[test]
+    fn under_budget_is_unchanged() {
+        let mut limiter = PowerLimiter::new(PowerConfig::default());
+        let frame = [rgbw(255, 255, 255, 255), rgbw(10, 20, 30, 0), rgbw(0, 0, 0, 0)];
+
+        // About 88 mA for one LED of each color, so ten of each stay well under 2A
+        let mut colors = frame;
+        limiter.limit(&mut colors, 10);
+
+        assert_eq!(colors, frame);
+    }
+
+    #[test]
+    fn zero_stays_zero() {
+        let mut limiter = PowerLimiter::new(PowerConfig::default());
+        let mut colors = [rgbw(255, 0, 255, 0), rgbw(0, 0, 0, 0), rgbw(0, 255, 0, 255)];
+
+        limiter.limit(&mut colors, 100);
+
+        assert_eq!((colors[0].g, colors[0].w, colors[2].r, colors[2].b), (0, 0, 0, 0));
+        assert_eq!(colors[1], rgbw(0, 0, 0, 0));
+        // The lit channels are dimmed but not off
+        assert!(colors[0].r > 0 && colors[0].r < 255);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::color::Color;
+
+/// A color for strips with a separate white LED
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Rgbw {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub w: u8,
+}
+
+impl From<Color> for Rgbw {
+    /// Leaves the white LED off, the intensity is ignored
+    fn from(c: Color) -> Rgbw {
+        Rgbw { r: c.r, g: c.g, b: c.b, w: 0 }
+    }
+}
+
+/// Moves as much of a color as possible onto the white LED.
+///
+/// White LEDs are rarely pure white, so we take the white LED's own color into account.
+/// Otherwise warm white LEDs would tint every color that has some white in it.
+#[derive(Debug, Clone)]
+pub struct WhiteExtractor {
+    /// The color of the white LED as seen through the red, green and blue LEDs, each between 0 and 1
+    white: [f64; 3],
+}
+
+impl WhiteExtractor {
+    pub fn new(white: [f64; 3]) -> WhiteExtractor {
+        WhiteExtractor { white }
+    }
+
+    /// This expects values that are proportional to the light output (after calibration)
+    pub fn extract(&self, c: Color) -> Rgbw {
+        let [wr, wg, wb] = self.white;
+        let rgb = [c.r as f64, c.g as f64, c.b as f64];
+
+        // The brightest the white LED can be without adding more of a channel than the color has
+        let w = rgb
+            .iter()
+            .zip(self.white.iter())
+            .filter(|(_, white)| **white > 0.0)
+            .map(|(v, white)| v / white)
+            .fold(f64::INFINITY, f64::min);
+        let w = if w.is_finite() { w.min(255.0) } else { 0.0 };
+
+        Rgbw {
+            r: (rgb[0] - w * wr).round().clamp(0.0, 255.0) as u8,
+            g: (rgb[1] - w * wg).round().clamp(0.0, 255.0) as u8,
+            b: (rgb[2] - w * wb).round().clamp(0.0, 255.0) as u8,
+            w: w.round().clamp(0.0, 255.0) as u8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WARM_WHITE: [f64; 3] = [1.0, 0.8, 0.6];
+
+    fn extract(white: [f64; 3], r: u8, g: u8, b: u8) -> [u8; 4] {
+        let rgbw = WhiteExtractor::new(white).extract(Color { i: u8::MAX, r, g, b });
+        [rgbw.r, rgbw.g, rgbw.b, rgbw.w]
+    }
+
+    #[test]
+    fn pure_white() {
+        assert_eq!(extract([1.0; 3], 255, 255, 255), [0, 0, 0, 255]);
+        // A warm white LED needs some help from green and blue
+        assert_eq!(extract(WARM_WHITE, 255, 255, 255), [0, 51, 102, 255]);
+    }
+
+    #[test]
+    fn saturated_primary() {
+        for &white in &[[1.0; 3], WARM_WHITE] {
+            assert_eq!(extract(white, 255, 0, 0), [255, 0, 0, 0]);
+            assert_eq!(extract(white, 0, 0, 255), [0, 0, 255, 0]);
+        }
+    }
+
+    #[test]
+    fn keeps_the_light_output() {
+        for color in [[255, 255, 255], [200, 150, 100], [10, 200, 30], [1, 1, 1], [0, 0, 0]].iter() {
+            let [r, g, b, w] = extract(WARM_WHITE, color[0], color[1], color[2]);
+
+            // The white LED plus what's left on each channel adds up to the original color
+            for ((out, orig), white) in [r, g, b].iter().zip(color.iter()).zip(WARM_WHITE.iter()) {
+                let total = *out as f64 + w as f64 * white;
+                assert!((total - *orig as f64).abs() <= 1.0, "{:?}", color);
+            }
+        }
+    }
+}
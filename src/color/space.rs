@@ -0,0 +1,564 @@
+//! Conversions between the color spaces we use for blending.
+//!
+//! Everything here works with floats between 0 and 1 (hues are in degrees). [`Color`] is only
+//! what we send to the lights, any math on colors should happen in one of these spaces.
+//! Blending in sRGB makes muddy, dark transitions so prefer [`Oklab`] or [`Oklch`] for that.
+
+use serde::Deserialize;
+
+use crate::color::Color;
+
+/// Red, green and blue in the (gamma encoded) sRGB color space
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Srgb {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+}
+
+/// Red, green and blue proportional to the amount of light emitted
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LinearRgb {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Hsv {
+    pub h: f64,
+    pub s: f64,
+    pub v: f64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Hsl {
+    pub h: f64,
+    pub s: f64,
+    pub l: f64,
+}
+
+/// A perceptually uniform color space, see https://bottosson.github.io/posts/oklab/
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Oklab {
+    pub l: f64,
+    pub a: f64,
+    pub b: f64,
+}
+
+/// The polar form of [`Oklab`] (lightness, chroma and hue)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Oklch {
+    pub l: f64,
+    pub c: f64,
+    pub h: f64,
+}
+
+/// Colors with less chroma than this are greys as far as hue goes
+const GREY_CHROMA: f64 = 1e-6;
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+/// Interpolate between two hues in degrees, going the short way around the circle
+fn lerp_hue(a: f64, b: f64, t: f64) -> f64 {
+    let mut diff = (b - a) % 360.0;
+    if diff > 180.0 {
+        diff -= 360.0;
+    } else if diff < -180.0 {
+        diff += 360.0;
+    }
+
+    (a + diff * t).rem_euclid(360.0)
+}
+
+/// Interpolate between the hues of two colors. A grey has no hue of its own, so it takes the other
+/// color's instead of fading through whatever hue it happens to have.
+fn lerp_hues(a: f64, a_chroma: f64, b: f64, b_chroma: f64, t: f64) -> f64 {
+    match (a_chroma < GREY_CHROMA, b_chroma < GREY_CHROMA) {
+        (true, false) => b,
+        (false, true) => a,
+        _ => lerp_hue(a, b, t),
+    }
+}
+
+/// The sRGB transfer function
+fn to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// The inverse of the sRGB transfer function
+fn from_linear(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+impl Srgb {
+    pub fn new(r: f64, g: f64, b: f64) -> Srgb {
+        Srgb { r, g, b }
+    }
+
+    /// Keep every channel between 0 and 1
+    pub fn clamp(self) -> Srgb {
+        Srgb::new(self.r.clamp(0.0, 1.0), self.g.clamp(0.0, 1.0), self.b.clamp(0.0, 1.0))
+    }
+
+    /// Blend two colors perceptually, `t` is between 0 (all `self`) and 1 (all `other`)
+    pub fn mix(self, other: Srgb, t: f64) -> Srgb {
+        Oklab::from(self).lerp(Oklab::from(other), t).into()
+    }
+
+    pub fn to_color(self, i: u8) -> Color {
+        let c = self.clamp();
+
+        Color {
+            i,
+            r: (c.r * 255.0).round() as u8,
+            g: (c.g * 255.0).round() as u8,
+            b: (c.b * 255.0).round() as u8,
+        }
+    }
+}
+
+impl LinearRgb {
+    pub fn new(r: f64, g: f64, b: f64) -> LinearRgb {
+        LinearRgb { r, g, b }
+    }
+
+    pub fn lerp(self, other: LinearRgb, t: f64) -> LinearRgb {
+        LinearRgb::new(lerp(self.r, other.r, t), lerp(self.g, other.g, t), lerp(self.b, other.b, t))
+    }
+}
+
+impl Hsv {
+    pub fn lerp(self, other: Hsv, t: f64) -> Hsv {
+        Hsv {
+            h: lerp_hues(self.h, self.s * self.v, other.h, other.s * other.v, t),
+            s: lerp(self.s, other.s, t),
+            v: lerp(self.v, other.v, t),
+        }
+    }
+}
+
+impl Hsl {
+    pub fn lerp(self, other: Hsl, t: f64) -> Hsl {
+        Hsl {
+            h: lerp_hues(self.h, self.s, other.h, other.s, t),
+            s: lerp(self.s, other.s, t),
+            l: lerp(self.l, other.l, t),
+        }
+    }
+}
+
+impl Oklab {
+    pub fn lerp(self, other: Oklab, t: f64) -> Oklab {
+        Oklab {
+            l: lerp(self.l, other.l, t),
+            a: lerp(self.a, other.a, t),
+            b: lerp(self.b, other.b, t),
+        }
+    }
+}
+
+impl Oklch {
+    pub fn lerp(self, other: Oklch, t: f64) -> Oklch {
+        Oklch {
+            l: lerp(self.l, other.l, t),
+            c: lerp(self.c, other.c, t),
+            h: lerp_hues(self.h, self.c, other.h, other.c, t),
+        }
+    }
+}
+
+/// The color space a gradient is blended in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Blend {
+    #[default]
+    Oklab,
+    /// Keeps the colors saturated between far apart hues, but passes through the hues in between
+    Oklch,
+    Hsv,
+    Hsl,
+    /// Mixes the light itself, which makes the middle of a gradient look too bright
+    Linear,
+}
+
+impl Blend {
+    /// Blend two colors, `t` is between 0 (all `a`) and 1 (all `b`)
+    pub fn mix(self, a: Srgb, b: Srgb, t: f64) -> Srgb {
+        match self {
+            Blend::Oklab => a.mix(b, t),
+            Blend::Oklch => Oklch::from(a).lerp(Oklch::from(b), t).into(),
+            Blend::Hsv => Hsv::from(a).lerp(Hsv::from(b), t).into(),
+            Blend::Hsl => Hsl::from(a).lerp(Hsl::from(b), t).into(),
+            Blend::Linear => LinearRgb::from(a).lerp(LinearRgb::from(b), t).into(),
+        }
+    }
+}
+
+impl From<Color> for Srgb {
+    fn from(c: Color) -> Srgb {
+        Srgb::new(c.r as f64 / 255.0, c.g as f64 / 255.0, c.b as f64 / 255.0)
+    }
+}
+
+impl From<Srgb> for LinearRgb {
+    fn from(c: Srgb) -> LinearRgb {
+        LinearRgb::new(to_linear(c.r), to_linear(c.g), to_linear(c.b))
+    }
+}
+
+impl From<LinearRgb> for Srgb {
+    fn from(c: LinearRgb) -> Srgb {
+        Srgb::new(from_linear(c.r), from_linear(c.g), from_linear(c.b))
+    }
+}
+
+impl From<LinearRgb> for Oklab {
+    fn from(c: LinearRgb) -> Oklab {
+        let l = (0.4122214708 * c.r + 0.5363325363 * c.g + 0.0514459929 * c.b).cbrt();
+        let m = (0.2119034982 * c.r + 0.6806995451 * c.g + 0.1073969566 * c.b).cbrt();
+        let s = (0.0883024619 * c.r + 0.2817188376 * c.g + 0.6299787005 * c.b).cbrt();
+
+        Oklab {
+            l: 0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+            a: 1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+            b: 0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+        }
+    }
+}
+
+impl From<Oklab> for LinearRgb {
+    fn from(c: Oklab) -> LinearRgb {
+        let l = (c.l + 0.3963377774 * c.a + 0.2158037573 * c.b).powi(3);
+        let m = (c.l - 0.1055613458 * c.a - 0.0638541728 * c.b).powi(3);
+        let s = (c.l - 0.0894841775 * c.a - 1.2914855480 * c.b).powi(3);
+
+        LinearRgb::new(
+            4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+            -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+            -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+        )
+    }
+}
+
+impl From<Srgb> for Oklab {
+    fn from(c: Srgb) -> Oklab {
+        LinearRgb::from(c).into()
+    }
+}
+
+impl From<Oklab> for Srgb {
+    fn from(c: Oklab) -> Srgb {
+        LinearRgb::from(c).into()
+    }
+}
+
+impl From<Oklab> for Oklch {
+    fn from(c: Oklab) -> Oklch {
+        Oklch {
+            l: c.l,
+            c: c.a.hypot(c.b),
+            h: c.b.atan2(c.a).to_degrees().rem_euclid(360.0),
+        }
+    }
+}
+
+impl From<Oklch> for Oklab {
+    fn from(c: Oklch) -> Oklab {
+        let h = c.h.to_radians();
+
+        Oklab {
+            l: c.l,
+            a: c.c * h.cos(),
+            b: c.c * h.sin(),
+        }
+    }
+}
+
+impl From<Srgb> for Oklch {
+    fn from(c: Srgb) -> Oklch {
+        Oklab::from(c).into()
+    }
+}
+
+impl From<Oklch> for Srgb {
+    fn from(c: Oklch) -> Srgb {
+        Oklab::from(c).into()
+    }
+}
+
+/// The hue (in degrees) and chroma shared by HSV and HSL
+fn hue_chroma(c: Srgb) -> (f64, f64, f64) {
+    let max = c.r.max(c.g).max(c.b);
+    let min = c.r.min(c.g).min(c.b);
+    let chroma = max - min;
+
+    let h = if chroma == 0.0 {
+        0.0
+    } else if max == c.r {
+        60.0 * ((c.g - c.b) / chroma).rem_euclid(6.0)
+    } else if max == c.g {
+        60.0 * ((c.b - c.r) / chroma + 2.0)
+    } else {
+        60.0 * ((c.r - c.g) / chroma + 4.0)
+    };
+
+    (h, chroma, max)
+}
+
+/// Build a color from its hue (in degrees), chroma and the amount added to every channel
+fn from_hue_chroma(h: f64, chroma: f64, m: f64) -> Srgb {
+    let h = h.rem_euclid(360.0) / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+
+    let (r, g, b) = match h as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+
+    Srgb::new(r + m, g + m, b + m)
+}
+
+impl From<Srgb> for Hsv {
+    fn from(c: Srgb) -> Hsv {
+        let (h, chroma, max) = hue_chroma(c);
+
+        Hsv {
+            h,
+            s: if max == 0.0 { 0.0 } else { chroma / max },
+            v: max,
+        }
+    }
+}
+
+impl From<Hsv> for Srgb {
+    fn from(c: Hsv) -> Srgb {
+        let chroma = c.v * c.s;
+
+        from_hue_chroma(c.h, chroma, c.v - chroma)
+    }
+}
+
+impl From<Srgb> for Hsl {
+    fn from(c: Srgb) -> Hsl {
+        let (h, chroma, max) = hue_chroma(c);
+        let l = max - chroma / 2.0;
+
+        Hsl {
+            h,
+            s: if l == 0.0 || l == 1.0 { 0.0 } else { chroma / (1.0 - (2.0 * l - 1.0).abs()) },
+            l,
+        }
+    }
+}
+
+impl From<Hsl> for Srgb {
+    fn from(c: Hsl) -> Srgb {
+        let chroma = (1.0 - (2.0 * c.l - 1.0).abs()) * c.s;
+
+        from_hue_chroma(c.h, chroma, c.l - chroma / 2.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn srgb_oklab_round_trip() {
+        for &(r, g, b) in &[(0.0, 0.0, 0.0), (1.0, 1.0, 1.0), (1.0, 0.0, 0.0), (0.2, 0.5, 0.9), (0.01, 0.7, 0.03)] {
+            let c = Srgb::from(Oklab::from(Srgb::new(r, g, b)));
+
+            assert_close(c.r, r);
+            assert_close(c.g, g);
+            assert_close(c.b, b);
+        }
+    }
+
+    #[test]
+    fn known_oklab_values() {
+        // From https://bottosson.github.io/posts/oklab/
+        let white = Oklab::from(Srgb::new(1.0, 1.0, 1.0));
+        assert_close(white.l, 1.0);
+        assert_close(white.a, 0.0);
+        assert_close(white.b, 0.0);
+
+        let red = Oklab::from(Srgb::new(1.0, 0.0, 0.0));
+        assert!((red.l - 0.6279554).abs() < 1e-4);
+        assert!((red.a - 0.2248631).abs() < 1e-4);
+        assert!((red.b - 0.1258463).abs() < 1e-4);
+    }
+
+    #[test]
+    fn linear_round_trip() {
+        for &c in &[0.0, 0.003, 0.04045, 0.5, 1.0] {
+            let linear = LinearRgb::from(Srgb::new(c, c, c));
+            assert_close(Srgb::from(linear).r, c);
+        }
+
+        assert_close(to_linear(0.5), 0.214041);
+    }
+
+    #[test]
+    fn color_conversion() {
+        let color = Color { i: 7, r: 255, g: 128, b: 0 };
+        let c = Srgb::from(color).to_color(color.i);
+
+        assert_eq!((c.i, c.r, c.g, c.b), (7, 255, 128, 0));
+        assert_eq!(Srgb::new(1.5, -0.5, 0.5).to_color(255).r, 255);
+    }
+
+    /// The colors that sit on the edges of the hue circle, plus some in between and greys
+    const COLORS: [(f64, f64, f64); 9] = [
+        (1.0, 0.0, 0.0),
+        (1.0, 0.0, 0.2),
+        (0.9, 0.9, 0.1),
+        (0.0, 1.0, 0.5),
+        (0.1, 0.3, 0.8),
+        (0.6, 0.1, 0.9),
+        (0.0, 0.0, 0.0),
+        (0.5, 0.5, 0.5),
+        (1.0, 1.0, 1.0),
+    ];
+
+    #[test]
+    fn hsv_round_trip() {
+        for &(r, g, b) in &COLORS {
+            let c = Srgb::from(Hsv::from(Srgb::new(r, g, b)));
+
+            assert_close(c.r, r);
+            assert_close(c.g, g);
+            assert_close(c.b, b);
+        }
+    }
+
+    #[test]
+    fn hsl_round_trip() {
+        for &(r, g, b) in &COLORS {
+            let c = Srgb::from(Hsl::from(Srgb::new(r, g, b)));
+
+            assert_close(c.r, r);
+            assert_close(c.g, g);
+            assert_close(c.b, b);
+        }
+    }
+
+    #[test]
+    fn oklch_round_trip() {
+        for &(r, g, b) in &COLORS {
+            let c = Srgb::from(Oklch::from(Srgb::new(r, g, b)));
+
+            assert_close(c.r, r);
+            assert_close(c.g, g);
+            assert_close(c.b, b);
+        }
+    }
+
+    #[test]
+    fn known_hsv_and_hsl_values() {
+        let hsv = Hsv::from(Srgb::new(0.0, 0.5, 1.0));
+        assert_close(hsv.h, 210.0);
+        assert_close(hsv.s, 1.0);
+        assert_close(hsv.v, 1.0);
+
+        let hsl = Hsl::from(Srgb::new(0.0, 0.5, 1.0));
+        assert_close(hsl.h, 210.0);
+        assert_close(hsl.s, 1.0);
+        assert_close(hsl.l, 0.5);
+    }
+
+    #[test]
+    fn hue_wraps_around() {
+        // Red with a touch of blue sits just under 360, not at a negative hue
+        let h = Hsv::from(Srgb::new(1.0, 0.0, 0.2)).h;
+        assert!(h > 300.0 && h < 360.0, "{}", h);
+
+        // Hues of 360 and more are back at red
+        for &h in &[0.0, 360.0, 720.0] {
+            let c = Srgb::from(Hsv { h, s: 1.0, v: 1.0 });
+            assert_close(c.r, 1.0);
+            assert_close(c.g, 0.0);
+            assert_close(c.b, 0.0);
+
+            let c = Srgb::from(Hsl { h, s: 1.0, l: 0.5 });
+            assert_close(c.r, 1.0);
+            assert_close(c.g, 0.0);
+            assert_close(c.b, 0.0);
+        }
+
+        for &(r, g, b) in &COLORS {
+            let h = Oklch::from(Srgb::new(r, g, b)).h;
+            assert!((0.0..360.0).contains(&h), "{}", h);
+        }
+    }
+
+    #[test]
+    fn hue_takes_the_short_way() {
+        assert_close(lerp_hue(350.0, 10.0, 0.5), 0.0);
+        assert_close(lerp_hue(10.0, 350.0, 0.5), 0.0);
+        assert_close(lerp_hue(10.0, 350.0, 0.25), 5.0);
+        assert_close(lerp_hue(340.0, 20.0, 0.75), 10.0);
+        assert_close(lerp_hue(90.0, 180.0, 0.5), 135.0);
+
+        let a = Oklch { l: 0.5, c: 0.1, h: 350.0 };
+        let b = Oklch { l: 0.7, c: 0.2, h: 30.0 };
+        let mid = a.lerp(b, 0.5);
+        assert_close(mid.l, 0.6);
+        assert_close(mid.c, 0.15);
+        assert_close(mid.h, 10.0);
+    }
+
+    const BLENDS: [Blend; 5] = [Blend::Oklab, Blend::Oklch, Blend::Hsv, Blend::Hsl, Blend::Linear];
+
+    #[test]
+    fn blends_end_at_each_color() {
+        let a = Srgb::new(0.9, 0.2, 0.1);
+        let b = Srgb::new(0.1, 0.3, 0.8);
+
+        for &blend in &BLENDS {
+            for &(t, expected) in &[(0.0, a), (1.0, b)] {
+                let c = blend.mix(a, b, t);
+
+                assert_close(c.r, expected.r);
+                assert_close(c.g, expected.g);
+                assert_close(c.b, expected.b);
+            }
+        }
+    }
+
+    #[test]
+    fn greys_take_the_other_hue() {
+        let black = Srgb::new(0.0, 0.0, 0.0);
+        let blue = Srgb::new(0.0, 0.0, 1.0);
+
+        // Without this the fade would start at red, the hue black happens to have
+        let hsv = Hsv::from(Blend::Hsv.mix(black, blue, 0.5));
+        assert_close(hsv.h, 240.0);
+        assert_close(hsv.v, 0.5);
+
+        let hsl = Hsl::from(Blend::Hsl.mix(blue, Srgb::new(1.0, 1.0, 1.0), 0.5));
+        assert_close(hsl.h, 240.0);
+
+        let blue_hue = Oklch::from(blue).h;
+        let mid = Oklch::from(Blend::Oklch.mix(black, blue, 0.5));
+        assert!((mid.h - blue_hue).abs() < 1e-3, "{} != {}", mid.h, blue_hue);
+    }
+}
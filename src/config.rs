@@ -0,0 +1,41 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::brightness::BrightnessConfig;
+use crate::control::ControlConfig;
+use crate::controller::music::MusicConfig;
+use crate::lights::LightsConfig;
+
+/// The path we read the config from when none is given on the command line
+pub const DEFAULT_CONFIG_PATH: &str = "lights.json";
+
+/// Everything that can be tweaked without recompiling.
+///
+/// Every field has a default so the config file only needs to mention what it changes.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub brightness: BrightnessConfig,
+    pub control: ControlConfig,
+    pub lights: LightsConfig,
+    pub music: MusicConfig,
+}
+
+impl Config {
+    /// Read the config from a JSON file, falling back to the defaults if the file doesn't exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let path = path.as_ref();
+
+        if !path.exists() {
+            log::info!("No config found at {}, using defaults", path.display());
+            return Ok(Config::default());
+        }
+
+        let file = File::open(path).with_context(|| format!("Error opening config {}", path.display()))?;
+
+        serde_json::from_reader(BufReader::new(file)).with_context(|| format!("Error parsing config {}", path.display()))
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::{bail, Context, Result};
+use futures::{sink::SinkExt, stream::StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+
+use crate::brightness::{Brightness, NightMode};
+use crate::controller::music::{ConnectionState, MusicStatus};
+
+/// Commands are tiny, anything longer than this isn't one and we hang up instead of buffering it
+const MAX_LINE_LENGTH: usize = 4096;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// The address to listen on for commands, like "127.0.0.1:7000". Disabled when unset.
+    pub address: Option<String>,
+}
+
+/// The control API takes one JSON command per line and answers each one with a status line
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    Status,
+    SetBrightness { brightness: f64 },
+    SetNightMode { mode: NightMode },
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    brightness: f64,
+    night_mode: NightMode,
+    night: bool,
+    music: ConnectionState,
+    /// The tags of the track that's playing, like its title and artist
+    now_playing: Option<HashMap<String, Value>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Binds before returning, so a bad address stops startup instead of going unnoticed
+pub async fn start(
+    config: ControlConfig,
+    brightness: Arc<Mutex<Brightness>>,
+    music: MusicStatus,
+) -> Result<Option<JoinHandle<()>>> {
+    let address = match config.address {
+        Some(address) => address,
+        None => return Ok(None),
+    };
+
+    let listener = TcpListener::bind(&address)
+        .await
+        .with_context(|| format!("Error binding the control API to {}", address))?;
+
+    log::info!("Listening for control commands on {}", address);
+
+    Ok(Some(tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    log::error!("Error accepting control connection, stopping the control API: {}", e);
+                    return;
+                }
+            };
+
+            log::debug!("Control connection from {}", peer);
+
+            let brightness = brightness.clone();
+            let music = music.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle(stream, brightness, music).await {
+                    log::error!("Error in control connection from {}: {}", peer, e);
+                }
+            });
+        }
+    })))
+}
+
+async fn handle(
+    stream: TcpStream,
+    brightness: Arc<Mutex<Brightness>>,
+    music: MusicStatus,
+) -> Result<()> {
+    let mut lines = Framed::new(stream, LinesCodec::new_with_max_length(MAX_LINE_LENGTH));
+
+    while let Some(line) = lines.next().await {
+        let line = match line {
+            Ok(line) => line,
+            Err(LinesCodecError::MaxLineLengthExceeded) => bail!("Command longer than {} bytes", MAX_LINE_LENGTH),
+            Err(e) => return Err(e.into()),
+        };
+
+        let response = match serde_json::from_str::<Command>(&line) {
+            Ok(command) => {
+                log::info!("Received control command {:?}", command);
+
+                // Taken before the brightness lock, which can't be held across an await
+                let connection = music.connection.lock().await.clone();
+                let now_playing = music.now_playing.lock().await.clone();
+
+                let mut brightness = brightness.lock().unwrap();
+
+                match command {
+                    Command::Status => (),
+                    Command::SetBrightness { brightness: value } => brightness.set_master(value),
+                    Command::SetNightMode { mode } => brightness.set_mode(mode),
+                }
+
+                serde_json::to_string(&Status {
+                    brightness: brightness.master(),
+                    night_mode: brightness.mode(),
+                    night: brightness.is_night(),
+                    music: connection,
+                    now_playing,
+                })?
+            }
+            Err(e) => serde_json::to_string(&ErrorResponse { error: e.to_string() })?,
+        };
+
+        lines.send(response).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,188 @@
+//! Captures from an ALSA device, like a line-in or the snd-aloop loopback, for rooms without a
+//! snapserver.
+
+use alsa::pcm::{Access, Format, Frames, HwParams, PCM};
+use alsa::{Direction, ValueOr};
+use anyhow::{Context, Result};
+use futures::future::{BoxFuture, FutureExt};
+use serde::Deserialize;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::controller::music::source::AudioSource;
+use crate::controller::music::Frame;
+
+/// The number of periods that can wait for the music controller
+const FRAME_QUEUE_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlsaConfig {
+    /// The capture device, like "hw:Loopback,1,0". The "plughw" devices convert for us when the
+    /// hardware doesn't support the rate or sample format we ask for.
+    pub device: String,
+    pub sample_rate: u32,
+    pub channels: u32,
+    /// The number of samples per channel ALSA hands us at a time, each period becomes a frame
+    pub period_size: usize,
+}
+
+impl Default for AlsaConfig {
+    fn default() -> Self {
+        AlsaConfig {
+            device: "default".to_string(),
+            sample_rate: 44100,
+            channels: 2,
+            period_size: 1024,
+        }
+    }
+}
+
+/// Captures on its own thread, since ALSA blocks while it waits for samples
+pub struct AlsaSource {
+    device: String,
+    frames: mpsc::Receiver<Result<Frame>>,
+}
+
+impl AlsaSource {
+    pub async fn open(config: AlsaConfig) -> Result<AlsaSource> {
+        let device = config.device.clone();
+
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let (frames_tx, frames) = mpsc::channel(FRAME_QUEUE_SIZE);
+
+        std::thread::spawn(move || match setup(&config) {
+            Ok((pcm, sample_rate, period_size)) => {
+                // Nobody's listening when opening was cancelled
+                if ready_tx.send(Ok(())).is_ok() {
+                    let result = capture(&pcm, config.channels as usize, sample_rate, period_size, &frames_tx);
+
+                    if let Err(e) = result {
+                        let _ = frames_tx.blocking_send(Err(e));
+                    }
+                }
+            }
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+            }
+        });
+
+        ready_rx.await??;
+
+        Ok(AlsaSource { device, frames })
+    }
+}
+
+impl AudioSource for AlsaSource {
+    fn name(&self) -> String {
+        self.device.clone()
+    }
+
+    fn next(&mut self) -> BoxFuture<'_, Result<Option<Frame>>> {
+        async move { self.frames.recv().await.transpose() }.boxed()
+    }
+}
+
+/// Open the device and set it up, along with the sample rate and period size it gave us
+fn setup(config: &AlsaConfig) -> Result<(PCM, usize, usize)> {
+    let pcm = PCM::new(&config.device, Direction::Capture, false)
+        .with_context(|| format!("Error opening {}", config.device))?;
+
+    {
+        let params = HwParams::any(&pcm)?;
+        params.set_access(Access::RWInterleaved)?;
+        params.set_format(Format::s16()).context("The device doesn't support 16 bit samples")?;
+        params
+            .set_channels(config.channels)
+            .with_context(|| format!("The device doesn't support {} channels", config.channels))?;
+        params.set_rate(config.sample_rate, ValueOr::Nearest)?;
+        params.set_period_size_near(config.period_size as Frames, ValueOr::Nearest)?;
+        pcm.hw_params(&params).context("Error configuring the device")?;
+    }
+
+    // The device might not have given us exactly what we asked for
+    let (sample_rate, period_size) = {
+        let params = pcm.hw_params_current()?;
+        (params.get_rate()? as usize, params.get_period_size()? as usize)
+    };
+
+    log::info!(
+        "Capturing {} channels at {}Hz from {}, {} samples at a time",
+        config.channels,
+        sample_rate,
+        config.device,
+        period_size
+    );
+
+    Ok((pcm, sample_rate, period_size))
+}
+
+/// Capture until the source is dropped
+fn capture(
+    pcm: &PCM,
+    channels: usize,
+    sample_rate: usize,
+    period_size: usize,
+    output: &mpsc::Sender<Result<Frame>>,
+) -> Result<()> {
+    let io = pcm.io_i16()?;
+    let mut buf = vec![0i16; period_size * channels];
+
+    loop {
+        let len = match io.readi(&mut buf) {
+            Ok(len) => len,
+            Err(e) => {
+                // We fell behind and ALSA dropped some samples, which we can live with
+                log::warn!("Error capturing, recovering: {}", e);
+                pcm.try_recover(e, true).context("Error recovering the capture device")?;
+                continue;
+            }
+        };
+
+        let mut samples = vec![Vec::with_capacity(len); channels];
+
+        for (n, &sample) in buf[..len * channels].iter().enumerate() {
+            samples[n % channels].push(sample as i32);
+        }
+
+        let frame = Frame {
+            timestamp: Instant::now(),
+            sample_rate,
+            channels: samples,
+            volume: 1.0,
+            muted: false,
+        };
+
+        if output.blocking_send(Ok(frame)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Needs ALSA's null device, run with `cargo test --features alsa -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn capture_from_null() {
+        let config = AlsaConfig {
+            device: "null".to_string(),
+            sample_rate: 48000,
+            channels: 2,
+            period_size: 256,
+        };
+
+        let mut source = AlsaSource::open(config).await.unwrap();
+
+        for _ in 0..4 {
+            let frame = source.next().await.unwrap().unwrap();
+
+            assert_eq!(frame.sample_rate, 48000);
+            assert_eq!(frame.channels.len(), 2);
+            assert!(frame.num_samples() > 0);
+            assert!(frame.channels.iter().all(|channel| channel.len() == frame.num_samples()));
+        }
+    }
+}
@@ -0,0 +1,411 @@
+//! Audio files split into the chunks a snapserver would send, so local file playback and the mock
+//! server can use the same decoders as a real stream.
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::path::Path;
+use time::{Duration, NumericalDuration};
+
+/// The length of each PCM chunk, the same as the real server's default
+const PCM_CHUNK_MS: usize = 20;
+
+/// The WAV format tags for integer samples, and for a format given by a GUID after the usual fields
+const WAVE_FORMAT_PCM: u32 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u32 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u32 = 0xFFFE;
+
+/// Audio split up into chunks the way a snapserver sends it
+pub struct AudioFile {
+    /// The name the server gives the codec
+    pub codec: &'static str,
+    pub header: Bytes,
+    /// Each chunk along with how long it plays for
+    pub chunks: Vec<(Bytes, Duration)>,
+}
+
+impl AudioFile {
+    /// Load a WAV or FLAC file. The whole file is read into memory and kept there, which is fine for
+    /// the songs we test with, but a long recording takes as much memory as it does disk space.
+    pub fn open(path: &Path) -> Result<AudioFile> {
+        let data = Bytes::from(std::fs::read(path).with_context(|| format!("Error reading {}", path.display()))?);
+
+        let file = if data.starts_with(b"fLaC") {
+            AudioFile::flac(data)
+        } else if data.starts_with(b"RIFF") {
+            AudioFile::wav(data)
+        } else {
+            Err(anyhow!("Only WAV and FLAC files are supported"))
+        };
+
+        file.with_context(|| format!("Error loading {}", path.display()))
+    }
+
+    /// Split up raw samples into chunks
+    pub fn pcm(header: Bytes, data: Bytes, sample_rate: usize, frame_size: usize) -> Result<AudioFile> {
+        let chunk_size = (sample_rate * PCM_CHUNK_MS / 1000).max(1) * frame_size;
+
+        let chunks: Vec<(Bytes, Duration)> = data
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let length = (chunk.len() / frame_size) as f64 / sample_rate as f64;
+                (data.slice_ref(chunk), length.seconds())
+            })
+            .collect();
+
+        if chunks.is_empty() {
+            bail!("There's no audio to stream");
+        }
+
+        Ok(AudioFile {
+            codec: "pcm",
+            header,
+            chunks,
+        })
+    }
+
+    fn wav(data: Bytes) -> Result<AudioFile> {
+        let mut format = None;
+        let mut pos = 12;
+
+        // Find the format, then the samples
+        while pos + 8 <= data.len() {
+            let id = &data[pos..pos + 4];
+            let size = read_le(&data, pos + 4, 4)? as usize;
+            let start = pos + 8;
+
+            if id == b"fmt " {
+                // The extensible format starts its GUID with the format tag it's standing in for
+                let format_tag = match read_le(&data, start, 2)? {
+                    WAVE_FORMAT_EXTENSIBLE => read_le(&data, start + 24, 2)?,
+                    format_tag => format_tag,
+                };
+
+                match format_tag {
+                    WAVE_FORMAT_PCM => (),
+                    WAVE_FORMAT_IEEE_FLOAT => bail!("Floating point samples aren't supported, only integer PCM"),
+                    format_tag => bail!("Unsupported WAV format {:#06x}, only integer PCM is supported", format_tag),
+                }
+
+                let num_channels = read_le(&data, start + 2, 2)? as usize;
+                let sample_rate = read_le(&data, start + 4, 4)? as usize;
+                let bits = read_le(&data, start + 14, 2)? as usize;
+
+                if !matches!(bits, 8 | 16 | 24 | 32) || num_channels == 0 {
+                    bail!("Unsupported format: {} channels of {} bits", num_channels, bits);
+                }
+
+                format = Some((sample_rate, num_channels, bits));
+            } else if id == b"data" {
+                let (sample_rate, num_channels, bits) =
+                    format.ok_or_else(|| anyhow!("The samples come before the format"))?;
+                let end = (start + size).min(data.len());
+
+                // Snapcast pads 24 bit samples to 4 bytes, but WAV files pack them into 3
+                let (samples, sample_size) = match bits {
+                    24 => (pad_24_bit(&data[start..end]), 4),
+                    bits => (data.slice(start..end), bits / 8),
+                };
+
+                return AudioFile::pcm(data.slice(..start), samples, sample_rate, num_channels * sample_size);
+            }
+
+            // Chunks are padded to an even size
+            pos = start + size + size % 2;
+        }
+
+        Err(anyhow!("There's no audio to stream"))
+    }
+
+    fn flac(data: Bytes) -> Result<AudioFile> {
+        let mut sample_rate = 0;
+        let mut pos = 4;
+
+        // The codec header is everything up to the first frame
+        loop {
+            let block_header = read_le(&data, pos, 1)?;
+            let size = (read_le(&data, pos + 1, 1)? << 16 | read_le(&data, pos + 2, 1)? << 8 | read_le(&data, pos + 3, 1)?)
+                as usize;
+
+            if block_header & 0x7F == 0 {
+                // The sample rate is the first 20 bits after 10 bytes of STREAMINFO
+                let start = pos + 4 + 10;
+                sample_rate = read_le(&data, start, 1)? << 12 | read_le(&data, start + 1, 1)? << 4 | read_le(&data, start + 2, 1)? >> 4;
+            }
+
+            pos += 4 + size;
+
+            // The top bit marks the last metadata block
+            if block_header & 0x80 != 0 {
+                break;
+            }
+        }
+
+        if sample_rate == 0 {
+            bail!("The FLAC file doesn't have a sample rate");
+        }
+
+        let mut chunks: Vec<(Bytes, Duration)> = Vec::new();
+        let mut start = pos;
+
+        while start < data.len() {
+            let block_size = match flac_block_size(&data[start..]) {
+                Some(block_size) => block_size,
+                // Like an ID3 tag tacked onto the end
+                None if !chunks.is_empty() => {
+                    log::warn!("Ignoring {} bytes after the last FLAC frame", data.len() - start);
+                    break;
+                }
+                None => bail!("The audio doesn't start with a FLAC frame"),
+            };
+
+            let end = flac_frame_end(&data, start)
+                .ok_or_else(|| anyhow!("Couldn't find the end of the FLAC frame at byte {}", start))?;
+
+            chunks.push((data.slice(start..end), (block_size as f64 / sample_rate as f64).seconds()));
+            start = end;
+        }
+
+        if chunks.is_empty() {
+            bail!("There's no audio to stream");
+        }
+
+        Ok(AudioFile {
+            codec: "flac",
+            header: data.slice(..pos),
+            chunks,
+        })
+    }
+}
+
+/// Sign extend packed 24 bit samples to 4 bytes each, like Snapcast sends them
+fn pad_24_bit(data: &[u8]) -> Bytes {
+    let mut padded = BytesMut::with_capacity(data.len() / 3 * 4);
+
+    for sample in data.chunks_exact(3) {
+        padded.put_i32_le(i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) >> 8);
+    }
+
+    padded.freeze()
+}
+
+/// Read a little endian number of `len` bytes
+fn read_le(data: &[u8], at: usize, len: usize) -> Result<u32> {
+    let bytes = data.get(at..at + len).ok_or_else(|| anyhow!("The file is truncated"))?;
+
+    Ok(bytes.iter().rev().fold(0, |n, &b| n << 8 | b as u32))
+}
+
+/// The block size of the FLAC frame starting at `data`, if it starts with a valid frame header
+fn flac_block_size(data: &[u8]) -> Option<u32> {
+    if data.len() < 6 || data[0] != 0xFF || data[1] & 0xFE != 0xF8 {
+        return None;
+    }
+
+    let block_code = data[2] >> 4;
+    let rate_code = data[2] & 0x0F;
+
+    if block_code == 0 || rate_code == 0x0F {
+        return None;
+    }
+
+    // The frame number is coded like UTF-8
+    let mut len = 4 + match data[4].leading_ones() {
+        0 => 1,
+        n @ 2..=7 => n as usize,
+        _ => return None,
+    };
+
+    let block_size = match block_code {
+        1 => 192,
+        2..=5 => 576 << (block_code - 2),
+        6 => {
+            len += 1;
+            *data.get(len - 1)? as u32 + 1
+        }
+        7 => {
+            len += 2;
+            ((*data.get(len - 2)? as u32) << 8 | *data.get(len - 1)? as u32) + 1
+        }
+        _ => 256 << (block_code - 8),
+    };
+
+    len += match rate_code {
+        12 => 1,
+        13 | 14 => 2,
+        _ => 0,
+    };
+
+    let crc = *data.get(len)?;
+
+    if crc8(&data[..len]) == crc {
+        Some(block_size)
+    } else {
+        None
+    }
+}
+
+/// Where the FLAC frame starting at `start` ends. Frames don't say how long they are, so this is
+/// where the next frame header starts, but audio data can look like a header by chance. The
+/// checksum at the end of each frame tells us which of those is the real one.
+fn flac_frame_end(data: &[u8], start: usize) -> Option<usize> {
+    // The checksum of everything before the last two bytes
+    let mut crc = 0;
+
+    for end in start + 2..=data.len() {
+        let footer = u16::from_be_bytes([data[end - 2], data[end - 1]]);
+
+        if footer == crc && (end == data.len() || flac_block_size(&data[end..]).is_some()) {
+            return Some(end);
+        }
+
+        crc = crc16(crc, data[end - 2]);
+    }
+
+    None
+}
+
+/// The checksum used by FLAC frame headers
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { crc << 1 ^ 0x07 } else { crc << 1 })
+    })
+}
+
+/// Add a byte to the checksum at the end of each FLAC frame
+fn crc16(crc: u16, byte: u8) -> u16 {
+    (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| if crc & 0x8000 != 0 { crc << 1 ^ 0x8005 } else { crc << 1 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::music::snap::decoder;
+
+    fn testdata(name: &str) -> Vec<u8> {
+        std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)).unwrap()
+    }
+
+    /// A frame with its checksum
+    fn with_crc16(mut frame: Vec<u8>) -> Vec<u8> {
+        let crc = frame.iter().fold(0, |crc, &byte| crc16(crc, byte));
+        frame.extend_from_slice(&crc.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn flac_frames() {
+        let chunk = testdata("flac.chunk");
+
+        let mut data = testdata("flac.header");
+        data.extend_from_slice(&chunk);
+        data.extend_from_slice(&chunk);
+
+        let file = AudioFile::flac(data.into()).unwrap();
+
+        assert_eq!(file.header, testdata("flac.header"));
+        assert_eq!(file.chunks.len(), 2);
+
+        for (payload, length) in file.chunks.iter() {
+            assert_eq!(payload, &chunk);
+            assert_eq!(*length, (4096.0 / 44100.0).seconds());
+        }
+    }
+
+    #[test]
+    fn flac_header_in_audio() {
+        // Enough of a real frame to include its header
+        let header = testdata("flac.chunk")[..16].to_vec();
+
+        // Audio data that happens to look like a frame header
+        let mut frame = header.clone();
+        frame.extend_from_slice(&[1, 2, 3]);
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&[4, 5, 6]);
+        let frame = with_crc16(frame);
+
+        let mut data = testdata("flac.header");
+        data.extend_from_slice(&frame);
+        data.extend_from_slice(&frame);
+
+        let file = AudioFile::flac(data.into()).unwrap();
+
+        assert_eq!(file.chunks.len(), 2);
+        assert!(file.chunks.iter().all(|(payload, _)| payload == &frame));
+    }
+
+    #[test]
+    fn flac_crc16() {
+        // The check value of the CRC-16/UMTS that FLAC uses
+        assert_eq!(b"123456789".iter().fold(0, |crc, &byte| crc16(crc, byte)), 0xFEE8);
+    }
+
+    /// A WAV file of mono 24 bit samples at 8kHz
+    fn wav_24_bit(samples: &[u8]) -> Vec<u8> {
+        wav_with_format(1, &[], samples)
+    }
+
+    /// A WAV file of mono 24 bit samples at 8kHz with the given format tag, and `extension` after
+    /// the usual format fields
+    fn wav_with_format(format_tag: u16, extension: &[u8], samples: &[u8]) -> Vec<u8> {
+        let fmt_size = 16 + extension.len() as u32;
+
+        let mut data = Vec::new();
+        data.extend_from_slice(b"RIFF");
+        data.extend_from_slice(&(20 + fmt_size + samples.len() as u32).to_le_bytes());
+        data.extend_from_slice(b"WAVEfmt ");
+        data.extend_from_slice(&fmt_size.to_le_bytes());
+        data.extend_from_slice(&format_tag.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&8000u32.to_le_bytes());
+        data.extend_from_slice(&(8000u32 * 3).to_le_bytes());
+        data.extend_from_slice(&3u16.to_le_bytes());
+        data.extend_from_slice(&24u16.to_le_bytes());
+        data.extend_from_slice(extension);
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        data.extend_from_slice(samples);
+        data
+    }
+
+    #[test]
+    fn wav_packed_24_bit() {
+        let samples: Vec<u8> = [0x7F_FFFFi32, -0x80_0000, 0x100].iter().flat_map(|s| s.to_le_bytes()[..3].to_vec()).collect();
+
+        let file = AudioFile::wav(wav_24_bit(&samples).into()).unwrap();
+
+        assert_eq!(file.chunks.len(), 1);
+        assert_eq!(file.chunks[0].1, (3.0 / 8000.0).seconds());
+
+        let mut decoder = decoder::from_header(file.codec, file.header.clone()).unwrap();
+        let channels = decoder.decode(file.chunks[0].0.clone()).unwrap();
+
+        assert_eq!(channels, vec![vec![32767, -32768, 1]]);
+    }
+
+    /// The extension of a WAVE_FORMAT_EXTENSIBLE format chunk for the given subformat
+    fn extensible(subformat: u16) -> Vec<u8> {
+        let mut extension = Vec::new();
+        extension.extend_from_slice(&22u16.to_le_bytes());
+        extension.extend_from_slice(&24u16.to_le_bytes()); // Valid bits
+        extension.extend_from_slice(&4u32.to_le_bytes()); // Front center
+        extension.extend_from_slice(&subformat.to_le_bytes());
+        extension.extend_from_slice(b"\x00\x00\x00\x00\x10\x00\x80\x00\x00\xAA\x00\x38\x9B\x71");
+        extension
+    }
+
+    #[test]
+    fn wav_extensible_pcm() {
+        let file = AudioFile::wav(wav_with_format(0xFFFE, &extensible(1), &[0; 6]).into()).unwrap();
+
+        assert_eq!(file.chunks.len(), 1);
+        assert_eq!(file.chunks[0].1, (2.0 / 8000.0).seconds());
+    }
+
+    #[test]
+    fn wav_rejects_other_formats() {
+        // Floating point, plainly and through the extensible format, then A-law
+        for (format_tag, extension) in [(3, vec![]), (0xFFFE, extensible(3)), (6, vec![])].iter() {
+            assert!(AudioFile::wav(wav_with_format(*format_tag, extension, &[0; 6]).into()).is_err());
+        }
+    }
+}
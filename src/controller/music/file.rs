@@ -0,0 +1,130 @@
+//! Plays a local WAV or FLAC file in real time, for demos and tuning without a snapserver.
+
+use anyhow::{Context, Result};
+use futures::future::{BoxFuture, FutureExt};
+use serde::Deserialize;
+use std::convert::TryInto;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+
+use crate::controller::music::audio_file::AudioFile;
+use crate::controller::music::snap::decoder::{self, AudioDecoder};
+use crate::controller::music::source::AudioSource;
+use crate::controller::music::Frame;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub path: PathBuf,
+    /// Stop at the end of the file instead of starting over
+    #[serde(default)]
+    pub once: bool,
+}
+
+pub struct FileSource {
+    config: FileConfig,
+    file: AudioFile,
+    decoder: Box<dyn AudioDecoder>,
+    /// The chunk we play next
+    index: usize,
+    start: Instant,
+    /// How much audio we've played so far
+    position: Duration,
+}
+
+impl FileSource {
+    pub fn open(config: FileConfig) -> Result<FileSource> {
+        let file = AudioFile::open(&config.path)?;
+        let decoder = decoder::from_header(file.codec, file.header.clone())?;
+
+        log::info!("Playing {}", config.path.display());
+
+        Ok(FileSource {
+            config,
+            file,
+            decoder,
+            index: 0,
+            start: Instant::now(),
+            position: Duration::from_secs(0),
+        })
+    }
+
+    async fn next_frame(&mut self) -> Result<Option<Frame>> {
+        if self.index == self.file.chunks.len() {
+            if self.config.once {
+                log::info!("Finished playing {}", self.config.path.display());
+                return Ok(None);
+            }
+
+            self.index = 0;
+        }
+
+        // Keep to real time, no matter how long decoding took
+        let timestamp = self.start + self.position;
+        sleep_until(timestamp).await;
+
+        let (payload, length) = &self.file.chunks[self.index];
+        self.index += 1;
+        self.position += (*length).try_into()?;
+
+        let channels = self.decoder.decode(payload.clone()).context("Error decoding the file")?;
+
+        Ok(Some(Frame {
+            timestamp: timestamp.into_std(),
+            sample_rate: self.decoder.sample_rate(),
+            channels,
+            volume: 1.0,
+            muted: false,
+        }))
+    }
+}
+
+impl AudioSource for FileSource {
+    fn name(&self) -> String {
+        self.config.path.display().to_string()
+    }
+
+    fn next(&mut self) -> BoxFuture<'_, Result<Option<Frame>>> {
+        self.next_frame().boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[tokio::test(start_paused = true)]
+    async fn plays_once() {
+        let config = FileConfig {
+            // A tenth of a second at 8kHz
+            path: Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join("tone.wav"),
+            once: true,
+        };
+
+        let start = Instant::now();
+        let mut source = FileSource::open(config).unwrap();
+        let mut frames = Vec::new();
+
+        while let Some(frame) = source.next().await.unwrap() {
+            frames.push(frame);
+        }
+
+        assert_eq!(frames.len(), 5);
+
+        for frame in frames.iter() {
+            assert_eq!(frame.sample_rate, 8000);
+            assert_eq!(frame.channels.len(), 2);
+            assert_eq!(frame.num_samples(), 160);
+        }
+
+        // One chunk every 20ms, in real time
+        for pair in frames.windows(2) {
+            assert_eq!(pair[1].timestamp - pair[0].timestamp, Duration::from_millis(20));
+        }
+
+        // The clock is paused, it only moves as far as playback waited for it
+        assert_eq!(start.elapsed(), Duration::from_millis(80));
+    }
+}
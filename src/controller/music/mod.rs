@@ -1,35 +1,65 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{bail, Context, Result};
 use num_complex::Complex;
 use num_traits::Zero;
-use rustfft::{algorithm::Radix4, Fft, FftDirection};
+use ringbuf::{Consumer, Producer, RingBuffer};
+use rustfft::{Fft, FftPlanner};
+use arc_swap::ArcSwap;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
 use std::f64::consts::PI;
 use std::ops::Range;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 use tokio::time::sleep;
 
 use crate::controller::Controller;
 use crate::color::{Color, NUM_LIGHTS, OFF};
-use crate::color::cmap::INFERNO_DATA;
+use crate::color::cmap::{Colormap, ColormapConfig};
 
+#[cfg(feature = "alsa")]
+mod alsa;
+mod audio_file;
+mod file;
+mod pcm;
+mod reconnect;
 mod snap;
+mod source;
+mod track;
 
-use snap::client::SnapClient;
+pub use reconnect::ConnectionState;
+use reconnect::ReconnectConfig;
+use snap::client::SnapConfig;
+#[cfg(any(test, feature = "mock"))]
+use snap::mock;
+use snap::rpc::{StreamState, StreamStatus};
+use source::{AudioSource, FatalError, SnapState, SourceConfig};
+use track::{PaletteConfig, Track};
 
-/// The number of times we try reconnecting to the snapserver before giving up
-const NUM_RETRIES: usize = 5;
-/// The number of samples per second (aka Hz)
-const SAMPLE_RATE: usize = 44100; // TODO get this from the server
+/// The number of samples waiting for the render loop, about a second and a half of audio
+const SAMPLE_BUFFER_SIZE: usize = 65536;
+/// Samples older than this waited in the buffer while another controller had the lights
+const MAX_SAMPLE_AGE: Duration = Duration::from_millis(250);
+/// How long we keep the lights while our stream is playing but no frames arrive, in ticks
+const MAX_FRAME_GAP: usize = 60;
+/// The number of samples per second (aka Hz) we assume until the server tells us otherwise
+const DEFAULT_SAMPLE_RATE: usize = 44100;
 /// The number of audio samples we keep from frame to frame and use for FFT
-const BUFFER_SIZE: usize = 4096; // TODO making this 8092 caused stack overflows...
-/// The size of each FFT bin in Hz
-const BIN_SIZE: f64 = SAMPLE_RATE as f64 / BUFFER_SIZE as f64;
+const DEFAULT_FFT_SIZE: usize = 4096;
 /// The rate at which each bar decreases (positive means down)
 const GRAVITY: f64 = 1.0; // TODO find the right value
 
+#[allow(dead_code)]
 const INTEGRAL: f64 = 0.77; // TODO
 
+/// The number of channels a light can follow
+const NUM_CHANNELS: usize = 4;
+/// The number of frequency bands we track for each channel
+const NUM_BANDS: usize = 3;
+
 const BAS_FREQ_LOW: f64 = 1.0;
 const BAS_FREQ_HIGH: f64 = 600.0;
 
@@ -39,34 +69,320 @@ const MID_FREQ_HIGH: f64 = 2_500.0;
 const TRE_FREQ_LOW: f64 = 2_000.0;
 const TRE_FREQ_HIGH: f64 = 20_000.0;
 
-// TODO these don't need to be constants...
-const BAS_INDEX_LOW: f64 = BAS_FREQ_LOW / BIN_SIZE;
-const BAS_INDEX_HIGH: f64 = BAS_FREQ_HIGH / BIN_SIZE;
-
-const MID_INDEX_LOW: f64 = MID_FREQ_LOW / BIN_SIZE;
-const MID_INDEX_HIGH: f64 = MID_FREQ_HIGH / BIN_SIZE;
-
-const TRE_INDEX_LOW: f64 = TRE_FREQ_LOW / BIN_SIZE;
-const TRE_INDEX_HIGH: f64 = TRE_FREQ_HIGH / BIN_SIZE;
-
-// EQ values to balance out each set of frequencies
+// EQ values to balance out each set of frequencies, for samples in the range of a 16 bit integer
 // TODO make these dynamic in some way
 const BAS_EQ: f64 = 1.0 / 5_000.0;
 const MID_EQ: f64 = 1.0 / 1_500.0;
 const TRE_EQ: f64 = 1.0 / 200.0;
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MusicConfig {
+    /// The number of audio samples used for each FFT.
+    /// Bigger sizes resolve the bass better but react more slowly.
+    pub fft_size: usize,
+    /// The number of new samples between each FFT, smaller values mean more overlap.
+    /// When unset we run one FFT for each tick that brought new samples.
+    pub hop_size: Option<usize>,
+    /// Which channel and band each light shows
+    pub lights: [LightConfig; NUM_LIGHTS],
+    /// The colormap used for every band without its own colormap
+    pub colormap: ColormapConfig,
+    /// Colormaps for specific bands
+    pub band_colormaps: HashMap<Band, ColormapConfig>,
+    /// What the lights do while the server has this client muted
+    pub when_muted: MutedMode,
+    /// How bright the lights are while muted when `when_muted` is "dim", between 0 and 1
+    pub muted_brightness: f64,
+    /// How the client's volume affects the lights
+    pub volume: VolumeMode,
+    pub source: SourceConfig,
+    pub snap: SnapConfig,
+    pub palette: PaletteConfig,
+    /// How we retry when the source fails, like when we lose the connection to the server
+    pub reconnect: ReconnectConfig,
+}
+
+impl Default for MusicConfig {
+    fn default() -> Self {
+        MusicConfig {
+            fft_size: DEFAULT_FFT_SIZE,
+            hop_size: None,
+            lights: [
+                LightConfig { channel: Channel::Mix, band: Band::Bass },
+                LightConfig { channel: Channel::Mix, band: Band::Mid },
+                LightConfig { channel: Channel::Mix, band: Band::Treble },
+            ],
+            colormap: ColormapConfig::default(),
+            band_colormaps: HashMap::new(),
+            when_muted: MutedMode::Inactive,
+            muted_brightness: 0.2,
+            volume: VolumeMode::Normalize,
+            source: SourceConfig::Snapcast,
+            snap: SnapConfig::default(),
+            palette: PaletteConfig::default(),
+            reconnect: ReconnectConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LightConfig {
+    pub channel: Channel,
+    pub band: Band,
+}
+
+/// The audio channel that a light follows
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    /// The average of every channel
+    Mix,
+    Left,
+    Right,
+    /// The difference between the left and right channels, which is loud when the stereo image is wide
+    Side,
+}
+
+impl Channel {
+    /// Derive this channel's `i`th sample from a frame's channels
+    fn sample(self, channels: &[Vec<i32>], i: usize) -> i32 {
+        // Mono streams play the same thing on both sides
+        let left = channels.first();
+        let right = channels.get(1).or(left);
+
+        match (self, left, right) {
+            // Widen before adding so loud samples don't overflow
+            (Channel::Mix, Some(_), _) => {
+                (channels.iter().map(|c| c[i] as i64).sum::<i64>() / channels.len() as i64) as i32
+            }
+            (Channel::Left, Some(left), _) => left[i],
+            (Channel::Right, _, Some(right)) => right[i],
+            (Channel::Side, Some(left), Some(right)) => ((left[i] as i64 - right[i] as i64) / 2) as i32,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MutedMode {
+    /// Hand the lights over to the next controller
+    Inactive,
+    /// Keep following the music, but dimmed
+    Dim,
+    /// Carry on as if nothing happened
+    Ignore,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VolumeMode {
+    /// Quieter music makes for dimmer lights, like what comes out of the speakers
+    Scale,
+    /// React the same at any volume. The server sends audio before the volume is applied,
+    /// so this is the stream as-is.
+    Normalize,
+}
+
+/// A range of frequencies that a light can follow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Band {
+    Bass = 0,
+    Mid = 1,
+    Treble = 2,
+}
+
+impl Band {
+    const ALL: [Band; NUM_BANDS] = [Band::Bass, Band::Mid, Band::Treble];
+
+    fn freqs(self) -> Range<f64> {
+        match self {
+            Band::Bass => BAS_FREQ_LOW..BAS_FREQ_HIGH,
+            Band::Mid => MID_FREQ_LOW..MID_FREQ_HIGH,
+            Band::Treble => TRE_FREQ_LOW..TRE_FREQ_HIGH,
+        }
+    }
+
+    fn eq(self) -> f64 {
+        match self {
+            Band::Bass => BAS_EQ,
+            Band::Mid => MID_EQ,
+            Band::Treble => TRE_EQ,
+        }
+    }
+}
+
+/// A block of audio samples along with the rate they should be played at
+#[derive(Debug)]
+pub struct Frame {
+    /// When the samples play, or were captured for live sources
+    pub timestamp: Instant,
+    pub sample_rate: usize,
+    /// One block of samples per channel, all the same length
+    pub channels: Vec<Vec<i32>>,
+    /// The volume the frame is played at, between 0 and 1
+    pub volume: f64,
+    pub muted: bool,
+}
+
+impl Frame {
+    /// The number of samples in each channel
+    pub fn num_samples(&self) -> usize {
+        self.channels.iter().map(Vec::len).min().unwrap_or(0)
+    }
+}
+
+/// One sample of every channel a light can follow, indexed by `Channel`
+type Sample = [i32; NUM_CHANNELS];
+
+/// How the newest frame is played. It's kept apart from the samples so that the render loop
+/// hears about it even when the buffer is full.
+struct Playback {
+    sample_rate: AtomicUsize,
+    /// Between 0 and 1, stored as the bits of an `f64`
+    volume: AtomicU64,
+    muted: AtomicBool,
+    /// Set when samples didn't fit in the buffer, so the ones waiting in it are stale
+    overflowed: AtomicBool,
+}
+
+impl Playback {
+    fn new() -> Playback {
+        Playback {
+            sample_rate: AtomicUsize::new(DEFAULT_SAMPLE_RATE),
+            volume: AtomicU64::new(1f64.to_bits()),
+            muted: AtomicBool::new(false),
+            overflowed: AtomicBool::new(false),
+        }
+    }
+
+    fn volume(&self) -> f64 {
+        f64::from_bits(self.volume.load(Ordering::SeqCst))
+    }
+}
+
+/// Hands samples over to the render loop
+pub struct FrameSender {
+    producer: Producer<Sample>,
+    /// The channels some light follows, the rest are left at 0
+    channels: Vec<Channel>,
+    playback: Arc<Playback>,
+}
+
+impl FrameSender {
+    /// The buffer fills up while another controller has the lights, the render loop throws out
+    /// the old samples once we're back
+    pub fn send(&mut self, frame: Frame) {
+        self.playback.muted.store(frame.muted, Ordering::SeqCst);
+        self.playback.volume.store(frame.volume.to_bits(), Ordering::SeqCst);
+
+        // Like the start of a burst from a pipe that nobody read for a while
+        if frame.timestamp.elapsed() > MAX_SAMPLE_AGE {
+            return;
+        }
+
+        // Stored before the samples go in, so new samples are never analyzed at the old rate
+        self.playback.sample_rate.store(frame.sample_rate, Ordering::SeqCst);
+
+        let len = frame.num_samples();
+        let channels = &self.channels;
+
+        let mut samples = (0..len).map(|i| {
+            let mut sample = [0; NUM_CHANNELS];
+            for channel in channels.iter() {
+                sample[*channel as usize] = channel.sample(&frame.channels, i);
+            }
+            sample
+        });
+
+        if self.producer.push_iter(&mut samples) < len && !self.playback.overflowed.swap(true, Ordering::SeqCst) {
+            log::debug!("The sample buffer is full, dropping samples until there's room");
+        }
+    }
+}
+
 pub struct MusicController {
-    frame: Arc<Mutex<Option<Vec<i32>>>>,
+    config: MusicConfig,
+    /// Every sample we received that we haven't analyzed yet, oldest first
+    samples: Consumer<Sample>,
+    playback: Arc<Playback>,
+    /// The status of our stream according to the server's JSON-RPC API
+    stream_state: Arc<ArcSwap<StreamState>>,
     current_color: [Color; NUM_LIGHTS],
     ticks_since_new_frame: usize,
 
+    /// The sample rate the FFT and frequency ranges are currently set up for
+    sample_rate: usize,
     hann_window: Vec<f64>,
-    fft: Radix4<f64>,
+    fft: Arc<dyn Fft<f64>>,
+
+    // These live on the heap, large FFT sizes overflow the stack otherwise
+    fft_buf: Vec<Complex<f64>>,
+    fft_scratch: Vec<Complex<f64>>,
+    /// The number of samples pushed onto the buffers since the last FFT
+    pending: usize,
+    /// One entry for each channel that the lights are following
+    channels: Vec<ChannelState>,
+    /// Reused every tick for the samples taken off the buffer, so rendering doesn't allocate
+    sample_buf: Vec<Sample>,
+    /// The samples of each of `channels` from this tick
+    channel_samples: Vec<Vec<i32>>,
+    /// The colormap for each band
+    colormaps: Vec<Colormap>,
+    /// The configured colormaps, for when a track has no album art palette
+    config_colormaps: Vec<Colormap>,
+    /// Tracks whose album art has loaded, numbered in the order they started playing
+    tracks: Receiver<(usize, Track)>,
+    /// The number of the track the colormaps are from
+    track_seq: usize,
+    status: MusicStatus,
+}
 
-    buf: [Complex<f64>; BUFFER_SIZE],
-    fft_buf: [Complex<f64>; BUFFER_SIZE],
-    fft_scratch: [Complex<f64>; BUFFER_SIZE],
-    spectrum_state: [SpectrumState; NUM_LIGHTS],
+/// What the control API shows about the music, kept up to date by the source task
+#[derive(Clone)]
+pub struct MusicStatus {
+    pub connection: Arc<Mutex<ConnectionState>>,
+    /// The tags of the track that's playing, without the album art
+    pub now_playing: Arc<Mutex<Option<HashMap<String, Value>>>>,
+}
+
+/// The analysis state of a single audio channel
+#[derive(Debug)]
+struct ChannelState {
+    channel: Channel,
+    buf: Vec<Complex<f64>>,
+    spectrum_state: [SpectrumState; NUM_BANDS],
+}
+
+impl ChannelState {
+    fn new(channel: Channel, fft_size: usize) -> ChannelState {
+        ChannelState {
+            channel,
+            buf: vec![Complex::zero(); fft_size],
+            spectrum_state: [
+                SpectrumState::new(Band::Bass.freqs(), Band::Bass.eq()),
+                SpectrumState::new(Band::Mid.freqs(), Band::Mid.eq()),
+                SpectrumState::new(Band::Treble.freqs(), Band::Treble.eq()),
+            ],
+        }
+    }
+
+    /// Push new samples onto the front of our buffer, dropping the oldest ones
+    fn push_samples(&mut self, samples: &[i32]) {
+        // Only the most recent samples fit in the buffer
+        let samples = &samples[samples.len().saturating_sub(self.buf.len())..];
+
+        let left_over = self.buf.len() - samples.len();
+        self.buf.copy_within(0..left_over, samples.len());
+
+        // Most recent samples first
+        for (v, x) in self.buf.iter_mut().zip(samples.iter().rev()) {
+            *v = Complex::new(*x as f64, 0.0);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -76,17 +392,22 @@ struct SpectrumState {
     val: f64,
     velocity: f64,
 
+    #[allow(dead_code)]
     sensitivity: f64,
+    #[allow(dead_code)]
     high_ticks: u8,
+    #[allow(dead_code)]
     low_ticks: u8,
     
     // Constants
     eq: f64,
+    freqs: Range<f64>,
+    // Depends on the sample rate
     freq_range: Range<usize>,
 }
 
 impl SpectrumState {
-    fn new(range: Range<usize>, eq: f64) -> SpectrumState {
+    fn new(freqs: Range<f64>, eq: f64) -> SpectrumState {
         SpectrumState {
             clamped_val: 0,
             val: 0.0,
@@ -96,198 +417,734 @@ impl SpectrumState {
             high_ticks: 0,
             low_ticks: 0,
 
-            eq: eq,
-            freq_range: range,
+            eq,
+            freqs,
+            freq_range: 0..0,
         }
     }
+
+    /// Compute the range of FFT bins covered by this state's frequencies
+    fn configure(&mut self, bin_size: f64, fft_size: usize) {
+        // Frequencies above the Nyquist frequency don't show up in the FFT
+        let max_index = fft_size / 2;
+
+        let high = ((self.freqs.end / bin_size).round() as usize).min(max_index);
+        // Make sure we always average at least one bin
+        let low = ((self.freqs.start / bin_size).round() as usize).min(high.saturating_sub(1));
+
+        self.freq_range = low..high.max(low + 1);
+    }
 }
 
 impl MusicController {
-    pub fn start() -> Self {
-        let frame = Arc::new(Mutex::new(None));
+    pub fn start(config: MusicConfig) -> Result<Self> {
+        let (controller, output, snap_state) = MusicController::new(config)?;
+
+        tokio::spawn(run(output, snap_state, controller.status.connection.clone(), controller.config.clone()));
+
+        Ok(controller)
+    }
+
+    /// The controller along with what the source task needs to feed it
+    fn new(config: MusicConfig) -> Result<(Self, FrameSender, SnapState)> {
+        if config.fft_size < 2 {
+            bail!("The FFT size must be at least 2, got {}", config.fft_size);
+        }
+
+        match config.hop_size {
+            Some(hop_size) if hop_size == 0 || hop_size > config.fft_size => {
+                bail!("The hop size must be between 1 and the FFT size ({}), got {}", config.fft_size, hop_size);
+            }
+            _ => (),
+        }
+
+        // Only analyze the channels that some light is actually following
+        let mut channels: Vec<ChannelState> = Vec::new();
+        for light in config.lights.iter() {
+            if !channels.iter().any(|state| state.channel == light.channel) {
+                channels.push(ChannelState::new(light.channel, config.fft_size));
+            }
+        }
+
+        let colormaps = Band::ALL
+            .iter()
+            .map(|band| {
+                config
+                    .band_colormaps
+                    .get(band)
+                    .unwrap_or(&config.colormap)
+                    .load()
+                    .with_context(|| format!("Error loading the colormap for {:?}", band))
+            })
+            .collect::<Result<Vec<Colormap>>>()?;
+
+        let fft = FftPlanner::new().plan_fft_forward(config.fft_size);
+
+        let (producer, samples) = RingBuffer::new(SAMPLE_BUFFER_SIZE).split();
+        let playback = Arc::new(Playback::new());
+        let stream_state = Arc::new(ArcSwap::from_pointee(StreamState::default()));
+
+        let (track_sender, tracks) = mpsc::channel();
+        let status = MusicStatus {
+            connection: Arc::new(Mutex::new(ConnectionState::Connecting)),
+            now_playing: Arc::new(Mutex::new(None)),
+        };
 
-        tokio::spawn(run(frame.clone()));
+        let output = FrameSender {
+            producer,
+            channels: channels.iter().map(|state| state.channel).collect(),
+            playback: playback.clone(),
+        };
+        let snap_state = SnapState {
+            stream_state: stream_state.clone(),
+            tracks: track_sender,
+            track_seq: Arc::new(AtomicUsize::new(0)),
+            now_playing: status.now_playing.clone(),
+        };
 
-        MusicController {
-            frame,
+        let mut controller = MusicController {
+            sample_buf: vec![[0; NUM_CHANNELS]; SAMPLE_BUFFER_SIZE],
+            channel_samples: vec![Vec::with_capacity(SAMPLE_BUFFER_SIZE); channels.len()],
+            channels,
+            config_colormaps: colormaps.clone(),
+            colormaps,
+            tracks,
+            track_seq: 0,
+            status,
+            fft_buf: vec![Complex::zero(); config.fft_size],
+            fft_scratch: vec![Complex::zero(); fft.get_inplace_scratch_len()],
+            pending: 0,
+            fft,
+
+            config,
+            samples,
+            playback,
+            stream_state,
             current_color: OFF,
             ticks_since_new_frame: usize::MAX,
 
-            hann_window: (0..BUFFER_SIZE)
-                .map(|i| 0.5 * (1.0 - (2.0 * PI * i as f64 / (BUFFER_SIZE - 1) as f64).cos()))
-                .collect(),
-            fft: Radix4::new(BUFFER_SIZE, FftDirection::Forward),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            hann_window: Vec::new(),
+        };
 
-            buf: [Complex::zero(); BUFFER_SIZE],
-            fft_buf: [Complex::zero(); BUFFER_SIZE],
-            fft_scratch: [Complex::zero(); BUFFER_SIZE],
+        controller.configure(DEFAULT_SAMPLE_RATE);
 
-            // TODO dynamic frequency ranges...
-            spectrum_state: [
-                SpectrumState::new(
-                    BAS_INDEX_LOW.round() as usize..BAS_INDEX_HIGH.round() as usize,
-                    BAS_EQ,
-                ), // BASS
-                SpectrumState::new(
-                    MID_INDEX_LOW.round() as usize..MID_INDEX_HIGH.round() as usize,
-                    MID_EQ,
-                ), // MID
-                SpectrumState::new(
-                    TRE_INDEX_LOW.round() as usize..TRE_INDEX_HIGH.round() as usize,
-                    TRE_EQ,
-                ), // TREBLE
-            ],
-        }
+        Ok((controller, output, snap_state))
     }
 
-    fn has_new_frame(&self) -> bool {
-        self.frame.blocking_lock().is_some()
+    /// The state of our connection to the server and what it's playing, kept up to date as we go
+    pub fn status(&self) -> MusicStatus {
+        self.status.clone()
     }
 
-    fn get_new_frame(&mut self) -> Option<Vec<i32>> {
-        // We are using a (possibly innefficient) mutex for this.
-        // Maybe there's a faster way of implementing a single reader single writer
-        // optional value.
-        self.frame.blocking_lock().take()
-    }
+    /// Set up everything that depends on the sample rate of the incoming audio
+    fn configure(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate;
 
-    fn process_frame(&mut self, frame: Vec<i32>) -> [Color; NUM_LIGHTS] {
-        // TODO could do this in the same step as copying it to the buffer and save memory
-        let in_buf: Vec<Complex<f64>> = frame
-            .iter()
-            .rev() // Most recent samples first
-            .map(|x| Complex::new(*x as f64, 0.0))
+        // The size of each FFT bin in Hz
+        let fft_size = self.config.fft_size;
+        let bin_size = sample_rate as f64 / fft_size as f64;
+
+        self.hann_window = (0..fft_size)
+            .map(|i| 0.5 * (1.0 - (2.0 * PI * i as f64 / (fft_size - 1) as f64).cos()))
             .collect();
 
-        // Push the new samples onto our buffers
-        let left_over = self.buf.len() - frame.len();
-        self.buf.copy_within(0..left_over, frame.len());
-        self.buf[..frame.len()].copy_from_slice(&in_buf);
+        self.pending = 0;
+
+        for channel in self.channels.iter_mut() {
+            // Samples recorded at the old rate would skew the spectrum
+            channel.buf.iter_mut().for_each(|v| *v = Complex::zero());
 
-        // Copy samples into FFT buffer
-        self.fft_buf.copy_from_slice(&self.buf[..BUFFER_SIZE]);
+            for state in channel.spectrum_state.iter_mut() {
+                state.configure(bin_size, fft_size);
+            }
+        }
+    }
+
+    fn has_new_samples(&self) -> bool {
+        !self.samples.is_empty()
+    }
 
-        // Apply hann windowing
-        // TODO could do this in the same step as copy_from_slice
-        // TODO is this necessary anymore?
-        self.fft_buf
-            .iter_mut()
-            .zip(self.hann_window.iter())
-            .for_each(|(v, h)| *v *= h);
+    /// Run an FFT over each channel's buffer and move each spectrum bar accordingly
+    fn analyze(&mut self) {
+        for channel in self.channels.iter_mut() {
+            // Copy samples into FFT buffer
+            self.fft_buf.copy_from_slice(&channel.buf);
 
-        // Perform the FFT
-        self.fft.process_with_scratch(&mut self.fft_buf, &mut self.fft_scratch);
+            // Apply hann windowing
+            // TODO could do this in the same step as copy_from_slice
+            // TODO is this necessary anymore?
+            self.fft_buf
+                .iter_mut()
+                .zip(self.hann_window.iter())
+                .for_each(|(v, h)| *v *= h);
 
-        let freqs = self
-            .fft_buf
-            .iter()
-            .take(BUFFER_SIZE / 2)
-            .map(|x| x.norm())
-            .collect::<Vec<f64>>();
-
-        // Iterate through different spectrum bars
-        for (i, state) in self.spectrum_state.iter_mut().enumerate() {
-            // Average the range of frequencies
-            let mut val = freqs[state.freq_range.clone()].iter().sum::<f64>() / state.freq_range.len() as f64;
-
-            // Apply EQ
-            val = val * state.eq;
-
-            // Apply gravity
-            state.velocity -= GRAVITY;
-
-            // Did this new value make us move up?
-            if val > state.val {
-                // How fast should we move up?
-                // This is an arbitrary formula that just seems to work well...
-                state.velocity = (val - state.val).sqrt();
+            // Perform the FFT
+            self.fft.process_with_scratch(&mut self.fft_buf, &mut self.fft_scratch);
+
+            let freqs = self
+                .fft_buf
+                .iter()
+                .take(self.config.fft_size / 2)
+                .map(|x| x.norm())
+                .collect::<Vec<f64>>();
+
+            // Iterate through different spectrum bars
+            for state in channel.spectrum_state.iter_mut() {
+                // Average the range of frequencies
+                let mut val = freqs[state.freq_range.clone()].iter().sum::<f64>() / state.freq_range.len() as f64;
+
+                // Apply EQ
+                val *= state.eq;
+
+                // Apply gravity
+                state.velocity -= GRAVITY;
+
+                // Did this new value make us move up?
+                if val > state.val {
+                    // How fast should we move up?
+                    // This is an arbitrary formula that just seems to work well...
+                    state.velocity = (val - state.val).sqrt();
+                }
+
+                state.val += state.velocity;
+
+                // Apply scaling
+                // TODO scale based on mean and stddev
+                state.clamped_val = state.val.clamp(0.0, 255.0) as u8;
             }
+        }
+    }
 
-            state.val += state.velocity;
+    /// Analyze the first `len` samples of `sample_buf`
+    fn process_samples(&mut self, len: usize) -> [Color; NUM_LIGHTS] {
+        let new_samples = &self.sample_buf[..len];
 
-            // Apply scaling
-            // TODO scale based on mean and stddev
-            state.clamped_val = state.val.clamp(0.0, 255.0) as u8;
+        for (state, samples) in self.channels.iter().zip(self.channel_samples.iter_mut()) {
+            samples.clear();
+            samples.extend(new_samples.iter().map(|sample| sample[state.channel as usize]));
         }
 
-        let mut colors = OFF;
+        if self.config.volume == VolumeMode::Scale {
+            let volume = self.playback.volume().clamp(0.0, 1.0);
+
+            for sample in self.channel_samples.iter_mut().flat_map(|samples| samples.iter_mut()) {
+                *sample = (*sample as f64 * volume) as i32;
+            }
+        }
 
-        for (color, state) in colors.iter_mut().zip(self.spectrum_state.iter()) {
-            let mapped = INFERNO_DATA[state.clamped_val as usize];
+        // Without a hop size we analyze once per tick
+        let hop_size = self.config.hop_size.unwrap_or(len);
 
-            *color = Color {
-                i: state.clamped_val,
-                r: (mapped[0] * 255.0) as u8,
-                g: (mapped[1] * 255.0) as u8,
-                b: (mapped[2] * 255.0) as u8,
+        let mut start = 0;
+        while start < len {
+            let take = (hop_size - self.pending).min(len - start);
+
+            for (state, samples) in self.channels.iter_mut().zip(self.channel_samples.iter()) {
+                state.push_samples(&samples[start..start + take]);
+            }
+            start += take;
+
+            self.pending += take;
+            if self.pending >= hop_size {
+                self.pending = 0;
+                self.analyze();
             }
         }
 
+        let mut colors = OFF;
+
+        for (color, light) in colors.iter_mut().zip(self.config.lights.iter()) {
+            let state = match self.channels.iter().find(|state| state.channel == light.channel) {
+                Some(channel) => &channel.spectrum_state[light.band as usize],
+                None => continue,
+            };
+
+            *color = self.colormaps[light.band as usize].color(state.clamped_val);
+        }
+
         colors
     }
 }
 
 impl Controller for MusicController {
     fn is_active(&self) -> bool {
+        if self.playback.muted.load(Ordering::SeqCst) && self.config.when_muted == MutedMode::Inactive {
+            return false;
+        }
+
+        // When the server can tell us whether our stream is playing we don't have to guess
+        match self.stream_state.load().status {
+            Some(StreamStatus::Playing) => return self.ticks_since_new_frame < MAX_FRAME_GAP || self.has_new_samples(),
+            Some(_) => return false,
+            None => (),
+        }
+
         // Snapcast produces some empty frames for a while after the music stops.
         // This buffer is more than enough.
         // However if our display is moving faster than our new frames we don't want to give up access.
-        self.has_new_frame() || self.ticks_since_new_frame < 10
+        self.has_new_samples() || self.ticks_since_new_frame < 10
     }
 
     fn tick(&mut self) -> [Color; NUM_LIGHTS] {
-        match self.get_new_frame() {
-            Some(frame) => {
-                self.ticks_since_new_frame = 0;
-                self.current_color = self.process_frame(frame)
-            },
-            None => self.ticks_since_new_frame += 1
+        // Only the newest track matters, and one whose album art was slow to load mustn't replace
+        // one that started after it
+        let track_seq = self.track_seq;
+        let newest = self.tracks.try_iter().filter(|(seq, _)| *seq > track_seq).max_by_key(|(seq, _)| *seq);
+
+        if let Some((seq, track)) = newest {
+            self.track_seq = seq;
+
+            log::info!(
+                "Now playing {} by {} from {}",
+                track.title().unwrap_or_else(|| "an unknown track".to_string()),
+                track.artist().unwrap_or_else(|| "an unknown artist".to_string()),
+                track.album().unwrap_or_else(|| "an unknown album".to_string())
+            );
+
+            self.colormaps = match track.colormap {
+                Some(colormap) => vec![colormap; NUM_BANDS],
+                None => self.config_colormaps.clone(),
+            };
+        }
+
+        if self.has_new_samples() {
+            self.ticks_since_new_frame = 0;
+
+            // A few samples left at the old rate don't matter, reconfiguring clears the FFT buffers anyway
+            let sample_rate = self.playback.sample_rate.load(Ordering::SeqCst);
+
+            if sample_rate != self.sample_rate {
+                log::info!("Sample rate changed from {} Hz to {} Hz", self.sample_rate, sample_rate);
+                self.configure(sample_rate);
+            }
+
+            let pending = self.samples.len();
+            let max_pending = (MAX_SAMPLE_AGE.as_secs_f64() * sample_rate as f64) as usize;
+
+            // What's left from before the buffer filled up is too old to show
+            if self.playback.overflowed.swap(false, Ordering::SeqCst) {
+                self.samples.discard(pending);
+            } else if pending > max_pending {
+                self.samples.discard(pending - max_pending);
+            }
+
+            // Every sample goes through the FFT, even when more than one frame arrived since the last tick
+            let len = self.samples.pop_slice(&mut self.sample_buf);
+
+            if len > 0 {
+                self.current_color = self.process_samples(len);
+            }
+        } else {
+            self.ticks_since_new_frame += 1;
+        }
+
+        if self.playback.muted.load(Ordering::SeqCst) && self.config.when_muted == MutedMode::Dim {
+            let mut colors = self.current_color;
+
+            for color in colors.iter_mut() {
+                color.i = (color.i as f64 * self.config.muted_brightness.clamp(0.0, 1.0)).round() as u8;
+            }
+
+            return colors;
         }
 
         self.current_color
     }
 }
 
-async fn run(mut output: Arc<Mutex<Option<Vec<i32>>>>) -> Result<()> {
-    let mut retries = 0;
+/// Keep the frames coming from the configured source, reopening it whenever something goes wrong
+async fn run(
+    mut output: FrameSender,
+    snap_state: SnapState,
+    connection: Arc<Mutex<ConnectionState>>,
+    config: MusicConfig,
+) -> Result<()> {
+    let snap = match with_mock(&config).await {
+        Ok(snap) => snap,
+        Err(e) => {
+            // Nobody looks at what this task returns, so make sure the error shows up
+            log::error!("{:#}", e);
+            *connection.lock().await = ConnectionState::Failed { error: format!("{:#}", e) };
+            return Err(e);
+        }
+    };
+
+    let reconnect = &config.reconnect;
+
+    // The number of failures in a row
+    let mut attempt = 0;
     loop {
-        log::info!("Connecting to SnapServer");
-        match SnapClient::discover().await {
-            Ok(client) => {
-                retries = 0;
+        *connection.lock().await = ConnectionState::Connecting;
 
-                log::info!("Successfully connected to SnapServer");
+        let error = match source::open(&config.source, &snap, &config.palette, &snap_state).await {
+            Ok(mut source) => {
+                *connection.lock().await = ConnectionState::Connected { source: source.name() };
 
-                // TODO different actions for different mainloop errors
-                match mainloop(client, &mut output).await {
-                    Ok(()) => return Ok(()),
+                let mut first_frame = None;
+
+                match mainloop(source.as_mut(), &mut output, &mut first_frame).await {
+                    Ok(()) => {
+                        log::info!("{} ran out of audio", source.name());
+                        *connection.lock().await = ConnectionState::Finished;
+                        return Ok(());
+                    }
                     Err(e) => {
-                        log::error!("Error in mainloop: {}", e);
+                        // Opening isn't enough, a source that fails right away keeps backing off
+                        if reconnect.was_healthy(first_frame) {
+                            attempt = 0;
+                        }
+
+                        e.context(format!("Lost {}", source.name()))
                     }
                 }
             }
-            Err(e) => {
-                retries += 1;
-                // TODO different actions for different errors
-                log::error!("Error connecting to SnapServer (Attempt #{}): {}", retries, e);
-            }
+            Err(e) => e.context("Error opening the audio source"),
+        };
+
+        attempt += 1;
+
+        if error.chain().any(|cause| cause.is::<FatalError>()) {
+            log::error!("{:#}, giving up since trying again won't help", error);
+            *connection.lock().await = ConnectionState::Failed { error: format!("{:#}", error) };
+            return Err(error);
         }
 
-        if retries > NUM_RETRIES {
-            return Err(anyhow!("Failed to connect to SnapServer after {} attempts", retries));
+        if !reconnect.should_retry(attempt) {
+            log::error!("{:#}, giving up after {} attempts", error, attempt);
+            *connection.lock().await = ConnectionState::Failed { error: format!("{:#}", error) };
+            return Err(error);
         }
 
-        log::info!("Sleeping 5 seconds before attempting to connect to SnapServer");
-        sleep(Duration::from_secs(5)).await;
+        let delay = reconnect.delay(attempt);
+        log::error!("{:#} (attempt #{}), trying again in {:.1} seconds", error, attempt, delay.as_secs_f64());
+
+        *connection.lock().await = ConnectionState::Retrying {
+            attempt,
+            error: format!("{:#}", error),
+            retry_in_ms: delay.as_millis() as u64,
+        };
+
+        sleep(delay).await;
     }
 }
 
-async fn mainloop(mut client: SnapClient, output: &mut Arc<Mutex<Option<Vec<i32>>>>) -> Result<()> {
-    while let Some(frame) = client.next().await.context("Error retrieving packet from snapclient")? {
-        log::trace!("Received frame from SnapServer");
-        
-        output.lock().await.replace(frame);
+/// Start the fake snapserver if there's one in the config, and point the client at wherever it's listening
+#[cfg(any(test, feature = "mock"))]
+async fn with_mock(config: &MusicConfig) -> Result<SnapConfig> {
+    let mut snap = config.snap.clone();
+
+    if let (SourceConfig::Snapcast, Some(mock)) = (&config.source, snap.mock.take()) {
+        let addr = mock::start(mock).await.context("Error starting the mock snapserver")?;
+
+        snap.server = Some(addr.to_string());
+        snap.fallback_to_discovery = false;
+    }
+
+    Ok(snap)
+}
+
+#[cfg(not(any(test, feature = "mock")))]
+async fn with_mock(config: &MusicConfig) -> Result<SnapConfig> {
+    Ok(config.snap.clone())
+}
+
+/// Pass on frames until the source runs out, noting when the first one arrived
+async fn mainloop(
+    source: &mut dyn AudioSource,
+    output: &mut FrameSender,
+    first_frame: &mut Option<Instant>,
+) -> Result<()> {
+    while let Some(frame) = source.next().await? {
+        first_frame.get_or_insert_with(Instant::now);
+        output.send(frame);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use file::FileConfig;
+    use snap::mock::MockConfig;
+    use std::path::Path;
+    use snap::source::SnapSource;
+
+    /// A config with a mock snapserver playing the test FLAC file on whatever port is free
+    fn mock_config() -> MusicConfig {
+        let mock = MockConfig {
+            address: "127.0.0.1:0".to_string(),
+            file: Some(Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join("tone.flac")),
+            buffer_ms: 100,
+            tags: vec![
+                ("title".to_string(), Value::from("Test Tone")),
+                ("artData".to_string(), Value::from("not really an image")),
+            ]
+            .into_iter()
+            .collect(),
+            ..MockConfig::default()
+        };
+
+        MusicConfig {
+            snap: SnapConfig {
+                host_name: Some("test".to_string()),
+                id: Some("test".to_string()),
+                rpc_port: None,
+                mock: Some(mock),
+                ..SnapConfig::default()
+            },
+            ..MusicConfig::default()
+        }
+    }
+
+    fn extract(channel: Channel, channels: &[Vec<i32>], len: usize) -> Vec<i32> {
+        (0..len).map(|i| channel.sample(channels, i)).collect()
+    }
+
+    #[test]
+    fn extract_channels() {
+        let channels = vec![vec![1, 10, -4], vec![3, -10, 4]];
+
+        assert_eq!(extract(Channel::Mix, &channels, 3), vec![2, 0, 0]);
+        assert_eq!(extract(Channel::Left, &channels, 3), vec![1, 10, -4]);
+        assert_eq!(extract(Channel::Right, &channels, 2), vec![3, -10]);
+        assert_eq!(extract(Channel::Side, &channels, 3), vec![-1, 10, -4]);
+    }
+
+    #[test]
+    fn extract_mono() {
+        let channels = vec![vec![5, -5]];
+
+        assert_eq!(extract(Channel::Mix, &channels, 2), vec![5, -5]);
+        assert_eq!(extract(Channel::Right, &channels, 2), vec![5, -5]);
+        assert_eq!(extract(Channel::Side, &channels, 2), vec![0, 0]);
+        assert_eq!(extract(Channel::Left, &[], 2), vec![0, 0]);
+    }
+
+    #[test]
+    fn extract_does_not_overflow() {
+        let channels = vec![vec![i32::MAX, i32::MIN], vec![i32::MAX, i32::MAX]];
+
+        assert_eq!(extract(Channel::Mix, &channels, 2), vec![i32::MAX, 0]);
+        assert_eq!(extract(Channel::Side, &channels, 2), vec![0, i32::MIN + 1]);
     }
 
-    Err(anyhow!("SnapClient connection unexpectedly closed, attempting to reconnect"))
-}
\ No newline at end of file
+    #[test]
+    fn push_samples_newest_first() {
+        let mut state = ChannelState::new(Channel::Mix, 4);
+
+        state.push_samples(&[1, 2]);
+        state.push_samples(&[3]);
+
+        let buf: Vec<f64> = state.buf.iter().map(|c| c.re).collect();
+        assert_eq!(buf, vec![3.0, 2.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn push_samples_longer_than_buffer() {
+        let mut state = ChannelState::new(Channel::Mix, 3);
+
+        state.push_samples(&[1, 2, 3, 4, 5]);
+
+        let buf: Vec<f64> = state.buf.iter().map(|c| c.re).collect();
+        assert_eq!(buf, vec![5.0, 4.0, 3.0]);
+    }
+
+    fn frame(samples: Vec<i32>, muted: bool) -> Frame {
+        Frame {
+            timestamp: Instant::now(),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            channels: vec![samples.clone(), samples],
+            volume: 1.0,
+            muted,
+        }
+    }
+
+    #[test]
+    fn every_sample_reaches_the_fft() {
+        let (mut controller, mut output, _) = MusicController::new(MusicConfig::default()).unwrap();
+
+        // Several frames between ticks
+        for n in 0..3 {
+            output.send(frame((n * 100..(n + 1) * 100).collect(), false));
+        }
+
+        assert!(controller.is_active());
+        controller.tick();
+
+        let buf: Vec<f64> = controller.channels[0].buf[..300].iter().map(|c| c.re).collect();
+        assert_eq!(buf, (0..300).rev().map(|n| n as f64).collect::<Vec<f64>>());
+    }
+
+    #[test]
+    fn only_followed_channels_are_derived() {
+        let (mut controller, mut output, _) = MusicController::new(MusicConfig::default()).unwrap();
+
+        output.send(Frame {
+            channels: vec![vec![10], vec![2]],
+            ..frame(vec![], false)
+        });
+
+        // Every light follows the mix by default
+        assert_eq!(controller.samples.pop(), Some([6, 0, 0, 0]));
+    }
+
+    #[test]
+    fn active_again_after_a_long_mute() {
+        let (mut controller, mut output, _) = MusicController::new(MusicConfig::default()).unwrap();
+
+        output.send(frame(vec![1000; 1024], false));
+        assert!(controller.is_active());
+        controller.tick();
+
+        // Nobody ticks an inactive controller, so the buffer fills up
+        for _ in 0..2 * SAMPLE_BUFFER_SIZE / 1024 {
+            output.send(frame(vec![1000; 1024], true));
+            assert!(!controller.is_active());
+        }
+
+        assert!(controller.samples.is_full());
+
+        output.send(frame(vec![1000; 1024], false));
+        assert!(controller.is_active());
+
+        // The samples from while we were muted are thrown out
+        controller.tick();
+        assert!(controller.samples.is_empty());
+
+        output.send(frame(vec![1000; 1024], false));
+        assert!(controller.is_active());
+        controller.tick();
+        assert!(controller.samples.is_empty());
+        assert!(controller.is_active());
+    }
+
+    #[test]
+    fn old_samples_are_skipped() {
+        let config = MusicConfig {
+            fft_size: 16384,
+            ..MusicConfig::default()
+        };
+
+        let (mut controller, mut output, _) = MusicController::new(config).unwrap();
+
+        // A second of audio in one go, like after another controller had the lights for a while
+        for n in 0..44 {
+            output.send(frame(vec![n; 1000], false));
+        }
+
+        controller.tick();
+
+        // Only the newest quarter second made it
+        let max_pending = (MAX_SAMPLE_AGE.as_secs_f64() * DEFAULT_SAMPLE_RATE as f64) as usize;
+        assert_eq!(controller.channels[0].buf[0].re, 43.0);
+        assert_eq!(controller.channels[0].buf[max_pending - 1].re, 32.0);
+        assert_eq!(controller.channels[0].buf[max_pending].re, 0.0);
+    }
+
+    #[tokio::test]
+    async fn file_played_once_finishes() {
+        let config = MusicConfig {
+            source: SourceConfig::File(FileConfig {
+                path: Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join("tone.wav"),
+                once: true,
+            }),
+            ..MusicConfig::default()
+        };
+
+        let (controller, output, snap_state) = MusicController::new(config.clone()).unwrap();
+
+        run(output, snap_state, controller.status.connection.clone(), config).await.unwrap();
+
+        assert_eq!(*controller.status.connection.lock().await, ConnectionState::Finished);
+        // Every sample of the file made it to the render loop
+        assert_eq!(controller.samples.len(), 800);
+    }
+
+    #[tokio::test]
+    async fn snap_source_from_mock() {
+        let state = SnapState {
+            stream_state: Arc::new(ArcSwap::from_pointee(StreamState::default())),
+            tracks: mpsc::channel().0,
+            track_seq: Arc::new(AtomicUsize::new(0)),
+            now_playing: Arc::new(Mutex::new(None)),
+        };
+        let now_playing = state.now_playing.clone();
+
+        let snap = with_mock(&mock_config()).await.unwrap();
+        let mut source = SnapSource::connect(&snap, PaletteConfig::default(), state).await.unwrap();
+        let mut last: Option<Instant> = None;
+
+        for _ in 0..10 {
+            let frame = tokio::time::timeout(Duration::from_secs(5), source.next())
+                .await
+                .expect("No frame from the mock")
+                .unwrap()
+                .unwrap();
+
+            // The test file has 20ms FLAC frames of the same tone on both channels
+            assert_eq!(frame.sample_rate, 48000);
+            assert_eq!(frame.channels.len(), 2);
+            assert_eq!(frame.num_samples(), 960);
+            assert_eq!(frame.channels[0], frame.channels[1]);
+            assert!(frame.channels[0].iter().any(|&sample| sample != 0));
+            assert_eq!(frame.volume, 1.0);
+            assert!(!frame.muted);
+
+            // Frames never come out before they're due, however slow the machine running the test
+            let early = frame.timestamp.saturating_duration_since(Instant::now());
+            assert!(early < Duration::from_millis(5), "Frame was {:?} early", early);
+
+            // Each one is due right after the one before it
+            if let Some(last) = last {
+                let gap = frame.timestamp.duration_since(last).as_secs_f64();
+                assert!((gap - 0.02).abs() < 0.005, "Frames were {}s apart", gap);
+            }
+
+            last = Some(frame.timestamp);
+        }
+
+        // The tags are passed on for the control API, apart from the album art
+        let now_playing = now_playing.lock().await.clone().expect("No tags from the mock");
+        assert_eq!(now_playing.get("title"), Some(&Value::from("Test Tone")));
+        assert!(!now_playing.contains_key("artData"));
+    }
+
+    #[tokio::test]
+    async fn controller_follows_mock() {
+        let config = mock_config();
+
+        let rgb = |color: &Color| (color.r, color.g, color.b);
+        let colormap = config.colormap.load().unwrap();
+        let mut controller = MusicController::start(config).unwrap();
+
+        // The controller blocks on its locks, which the runtime's own threads can't do
+        let colors = tokio::task::spawn_blocking(move || {
+            let mut colors = Vec::new();
+            let deadline = Instant::now() + Duration::from_secs(10);
+
+            // Tick at the render loop's 60 fps until we've seen a second of music, or give up
+            while colors.len() < 60 && Instant::now() < deadline {
+                if controller.is_active() {
+                    colors.push(controller.tick());
+                }
+
+                std::thread::sleep(Duration::from_millis(16));
+            }
+
+            colors
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(colors.len(), 60, "Only active for {} ticks", colors.len());
+
+        // Every light shows a color from the colormap, and the tone lifts at least one off the bottom
+        let valid: Vec<(u8, (u8, u8, u8))> = (0..=u8::MAX).map(|val| (val, rgb(&colormap.color(val)))).collect();
+        let colors: Vec<&Color> = colors.iter().flatten().collect();
+
+        assert!(colors.iter().all(|color| valid.contains(&(color.i, rgb(color)))));
+        assert!(colors.iter().any(|color| color.i > 0));
+    }
+}
@@ -0,0 +1,383 @@
+//! Reads raw PCM from stdin, a named pipe or UDP, for players that can write their output
+//! somewhere, like MPD's fifo output, shairport-sync's pipe backend or `arecord`.
+
+use anyhow::{Context, Result};
+use futures::future::{BoxFuture, FutureExt};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::UdpSocket;
+
+use crate::controller::music::source::{AudioSource, FatalError};
+use crate::controller::music::Frame;
+
+/// The most a UDP datagram can hold
+const MAX_DATAGRAM_SIZE: usize = 65536;
+const READ_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PcmConfig {
+    pub input: PcmInput,
+    pub format: SampleFormat,
+    pub sample_rate: usize,
+    /// The channels are interleaved
+    pub channels: usize,
+    /// How much audio goes into each frame, in milliseconds
+    pub chunk_ms: usize,
+}
+
+impl Default for PcmConfig {
+    fn default() -> Self {
+        PcmConfig {
+            input: PcmInput::Stdin,
+            format: SampleFormat::S16Le,
+            sample_rate: 44100,
+            channels: 2,
+            chunk_ms: 20,
+        }
+    }
+}
+
+/// Where the samples come from. They should arrive in real time, there's no buffering to smooth
+/// out bursts.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum PcmInput {
+    Stdin,
+    /// A named pipe, opened again whenever the writer closes it
+    Fifo { path: PathBuf },
+    /// Datagrams sent to this address, each holding whole frames
+    Udp { address: String },
+}
+
+impl fmt::Display for PcmInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PcmInput::Stdin => write!(f, "stdin"),
+            PcmInput::Fifo { path } => write!(f, "{}", path.display()),
+            PcmInput::Udp { address } => write!(f, "udp://{}", address),
+        }
+    }
+}
+
+/// Named like ALSA and ffmpeg name them, all little endian
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum SampleFormat {
+    #[serde(rename = "u8")]
+    U8,
+    #[serde(rename = "s16le")]
+    S16Le,
+    /// Packed into 3 bytes
+    #[serde(rename = "s24le")]
+    S24Le,
+    #[serde(rename = "s32le")]
+    S32Le,
+    #[serde(rename = "f32le")]
+    F32Le,
+}
+
+impl SampleFormat {
+    fn size(self) -> usize {
+        match self {
+            SampleFormat::U8 => 1,
+            SampleFormat::S16Le => 2,
+            SampleFormat::S24Le => 3,
+            SampleFormat::S32Le | SampleFormat::F32Le => 4,
+        }
+    }
+
+    /// Read one sample, scaled to the range of a 16 bit integer like the Snapcast decoders do
+    fn read(self, bytes: &[u8]) -> i32 {
+        match self {
+            SampleFormat::U8 => (bytes[0] as i32 - 128) << 8,
+            SampleFormat::S16Le => i16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+            // Put the sample in the top 3 bytes so the shift extends the sign
+            SampleFormat::S24Le => i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 16,
+            SampleFormat::S32Le => i32::from_le_bytes(bytes.try_into().unwrap()) >> 16,
+            SampleFormat::F32Le => {
+                let sample = f32::from_le_bytes(bytes.try_into().unwrap()) as f64;
+                (sample.clamp(-1.0, 1.0) * i16::MAX as f64) as i32
+            }
+        }
+    }
+}
+
+/// Collects bytes until there's enough for a frame
+struct Chunker {
+    format: SampleFormat,
+    sample_rate: usize,
+    channels: usize,
+    /// The number of bytes in each frame
+    chunk_size: usize,
+    pending: Vec<u8>,
+}
+
+impl Chunker {
+    fn new(config: &PcmConfig) -> Chunker {
+        let samples = (config.sample_rate * config.chunk_ms / 1000).max(1);
+
+        Chunker {
+            format: config.format,
+            sample_rate: config.sample_rate,
+            channels: config.channels,
+            chunk_size: samples * config.channels * config.format.size(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// The size of one sample for every channel
+    fn frame_size(&self) -> usize {
+        self.channels * self.format.size()
+    }
+
+    fn push(&mut self, data: &[u8], frames: &mut VecDeque<Frame>) {
+        self.pending.extend_from_slice(data);
+
+        let now = Instant::now();
+        let num_chunks = self.pending.len() / self.chunk_size;
+        let samples_per_chunk = self.chunk_size / self.frame_size();
+        let chunk_length = Duration::from_secs_f64(samples_per_chunk as f64 / self.sample_rate as f64);
+
+        for (n, chunk) in self.pending.chunks_exact(self.chunk_size).enumerate() {
+            let mut channels = vec![Vec::with_capacity(samples_per_chunk); self.channels];
+
+            for (i, sample) in chunk.chunks_exact(self.format.size()).enumerate() {
+                channels[i % self.channels].push(self.format.read(sample));
+            }
+
+            // The last chunk is playing now, the ones before it played earlier
+            frames.push_back(Frame {
+                timestamp: now - chunk_length * (num_chunks - n - 1) as u32,
+                sample_rate: self.sample_rate,
+                channels,
+                volume: 1.0,
+                muted: false,
+            });
+        }
+
+        self.pending.drain(..num_chunks * self.chunk_size);
+    }
+
+    /// Datagrams each hold whole frames, a partial one would shift every sample after it into the
+    /// wrong channel
+    fn push_datagram(&mut self, data: &[u8], frames: &mut VecDeque<Frame>) {
+        let len = data.len() - data.len() % self.frame_size();
+
+        if len < data.len() {
+            log::debug!("Dropping {} bytes of a partial frame", data.len() - len);
+        }
+
+        self.push(&data[..len], frames);
+    }
+}
+
+enum Input {
+    Stream(Box<dyn AsyncRead + Unpin + Send>),
+    Udp(UdpSocket),
+}
+
+pub struct PcmSource {
+    config: PcmConfig,
+    input: Input,
+    chunker: Chunker,
+    /// Frames we've read but haven't handed out yet
+    frames: VecDeque<Frame>,
+    buf: Vec<u8>,
+}
+
+impl PcmSource {
+    pub async fn open(config: PcmConfig) -> Result<PcmSource> {
+        if config.channels == 0 || config.sample_rate == 0 {
+            return Err(FatalError("PCM input needs at least one channel and a sample rate".to_string()).into());
+        }
+
+        let (input, buf_size) = match &config.input {
+            PcmInput::Stdin => (Input::Stream(Box::new(tokio::io::stdin())), READ_SIZE),
+            PcmInput::Fifo { path } => (open_fifo(path).await?, READ_SIZE),
+            PcmInput::Udp { address } => {
+                let socket = UdpSocket::bind(address)
+                    .await
+                    .with_context(|| format!("Error binding to {}", address))?;
+
+                (Input::Udp(socket), MAX_DATAGRAM_SIZE)
+            }
+        };
+
+        log::info!(
+            "Reading {} channels of {:?} at {}Hz from {}",
+            config.channels,
+            config.format,
+            config.sample_rate,
+            config.input
+        );
+
+        Ok(PcmSource {
+            chunker: Chunker::new(&config),
+            config,
+            input,
+            frames: VecDeque::new(),
+            buf: vec![0; buf_size],
+        })
+    }
+
+    async fn next_frame(&mut self) -> Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = self.frames.pop_front() {
+                return Ok(Some(frame));
+            }
+
+            match &mut self.input {
+                Input::Stream(reader) => {
+                    let len = reader.read(&mut self.buf).await.context("Error reading PCM")?;
+
+                    if len == 0 {
+                        match &self.config.input {
+                            // Players like MPD close the pipe whenever they stop
+                            PcmInput::Fifo { path } => {
+                                log::info!("The writer closed {}, waiting for the next one", path.display());
+                                self.input = open_fifo(path).await?;
+                            }
+                            _ => {
+                                log::info!("Reached the end of {}", self.config.input);
+                                return Ok(None);
+                            }
+                        }
+
+                        continue;
+                    }
+
+                    self.chunker.push(&self.buf[..len], &mut self.frames);
+                }
+                Input::Udp(socket) => {
+                    let len = socket.recv(&mut self.buf).await.context("Error receiving PCM")?;
+                    self.chunker.push_datagram(&self.buf[..len], &mut self.frames);
+                }
+            }
+        }
+    }
+}
+
+impl AudioSource for PcmSource {
+    fn name(&self) -> String {
+        self.config.input.to_string()
+    }
+
+    fn next(&mut self) -> BoxFuture<'_, Result<Option<Frame>>> {
+        self.next_frame().boxed()
+    }
+}
+
+/// This waits for a writer
+async fn open_fifo(path: &Path) -> Result<Input> {
+    let file = File::open(path).await.with_context(|| format!("Error opening {}", path.display()))?;
+
+    Ok(Input::Stream(Box::new(file)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 10 samples per chunk of 16 bit stereo
+    fn chunker() -> Chunker {
+        Chunker::new(&PcmConfig {
+            sample_rate: 1000,
+            chunk_ms: 10,
+            ..PcmConfig::default()
+        })
+    }
+
+    /// Interleaved 16 bit stereo, counting up on the left and down on the right
+    fn stereo(samples: std::ops::Range<i16>) -> Vec<u8> {
+        samples.flat_map(|n| [n.to_le_bytes(), (-n).to_le_bytes()].concat()).collect()
+    }
+
+    #[test]
+    fn read_u8() {
+        assert_eq!(SampleFormat::U8.read(&[0]), -32768);
+        assert_eq!(SampleFormat::U8.read(&[128]), 0);
+        assert_eq!(SampleFormat::U8.read(&[255]), 32512);
+    }
+
+    #[test]
+    fn read_s16le() {
+        assert_eq!(SampleFormat::S16Le.read(&[0x34, 0x12]), 0x1234);
+        assert_eq!(SampleFormat::S16Le.read(&[0x00, 0x80]), -32768);
+    }
+
+    #[test]
+    fn read_s24le_extends_the_sign() {
+        assert_eq!(SampleFormat::S24Le.read(&[0xFF, 0xFF, 0x7F]), 32767);
+        assert_eq!(SampleFormat::S24Le.read(&[0x00, 0x00, 0x80]), -32768);
+        assert_eq!(SampleFormat::S24Le.read(&[0x00, 0x01, 0x00]), 1);
+        assert_eq!(SampleFormat::S24Le.read(&[0xFF, 0xFF, 0xFF]), -1);
+        assert_eq!(SampleFormat::S24Le.read(&[0x00, 0xFF, 0xFF]), -1);
+    }
+
+    #[test]
+    fn read_s32le() {
+        assert_eq!(SampleFormat::S32Le.read(&i32::MAX.to_le_bytes()), 32767);
+        assert_eq!(SampleFormat::S32Le.read(&i32::MIN.to_le_bytes()), -32768);
+    }
+
+    #[test]
+    fn read_f32le_clamps() {
+        assert_eq!(SampleFormat::F32Le.read(&0.5f32.to_le_bytes()), 16383);
+        assert_eq!(SampleFormat::F32Le.read(&1.0f32.to_le_bytes()), 32767);
+        assert_eq!(SampleFormat::F32Le.read(&2.0f32.to_le_bytes()), 32767);
+        assert_eq!(SampleFormat::F32Le.read(&(-2.0f32).to_le_bytes()), -32767);
+    }
+
+    #[test]
+    fn chunker_waits_for_whole_chunks() {
+        let mut chunker = chunker();
+        let mut frames = VecDeque::new();
+        let data = stereo(0..15);
+
+        chunker.push(&data[..30], &mut frames);
+        assert!(frames.is_empty());
+
+        chunker.push(&data[30..], &mut frames);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].sample_rate, 1000);
+        assert_eq!(frames[0].channels, vec![(0..10).collect::<Vec<i32>>(), (0..10).map(|n| -n).collect()]);
+
+        // The rest waits for the next chunk
+        assert_eq!(chunker.pending, data[40..]);
+    }
+
+    #[test]
+    fn chunker_dates_a_burst_back() {
+        let mut chunker = chunker();
+        let mut frames = VecDeque::new();
+
+        chunker.push(&stereo(0..30), &mut frames);
+
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[2].channels[0][0], 20);
+        assert_eq!(frames[1].timestamp - frames[0].timestamp, Duration::from_millis(10));
+        assert_eq!(frames[2].timestamp - frames[1].timestamp, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn partial_datagrams_are_trimmed() {
+        let mut chunker = chunker();
+        let mut frames = VecDeque::new();
+
+        // Half of the last frame went missing
+        let mut data = stereo(0..5);
+        data.extend_from_slice(&[0xAA, 0xBB]);
+
+        chunker.push_datagram(&data, &mut frames);
+        chunker.push_datagram(&stereo(5..10), &mut frames);
+
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].channels, vec![(0..10).collect::<Vec<i32>>(), (0..10).map(|n| -n).collect()]);
+    }
+}
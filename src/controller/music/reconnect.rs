@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectConfig {
+    /// How long to wait after the first failure, the wait doubles after every failure in a row
+    pub initial_delay_ms: u64,
+    /// The longest we wait between attempts
+    pub max_delay_ms: u64,
+    /// Each wait is randomly up to this fraction longer or shorter, so lights that lost the same
+    /// server don't all come back at once
+    pub jitter: f64,
+    /// Give up after this many failures in a row, we keep trying forever when unset
+    pub max_retries: Option<usize>,
+    /// A source has to deliver audio for this long before its failure stops counting as one more
+    /// in a row, so a server that accepts us and then drops us right away still backs off
+    pub reset_after_ms: u64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_delay_ms: 1_000,
+            max_delay_ms: 60_000,
+            jitter: 0.2,
+            max_retries: None,
+            reset_after_ms: 10_000,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// How long to wait before the given attempt, starting at 1
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(32) as i32;
+        let delay = (self.initial_delay_ms as f64 * 2f64.powi(doublings)).min(self.max_delay_ms as f64);
+
+        let jitter = self.jitter.clamp(0.0, 1.0) * (random() * 2.0 - 1.0);
+
+        Duration::from_millis((delay * (1.0 + jitter)) as u64)
+    }
+
+    pub fn should_retry(&self, attempt: usize) -> bool {
+        self.max_retries.is_none_or(|max_retries| attempt <= max_retries)
+    }
+
+    /// Whether a source that delivered its first frame at `first_frame` was up long enough to
+    /// start counting failures again
+    pub fn was_healthy(&self, first_frame: Option<Instant>) -> bool {
+        matches!(first_frame, Some(first_frame) if first_frame.elapsed() >= Duration::from_millis(self.reset_after_ms))
+    }
+}
+
+/// A random number between 0 and 1. Every `RandomState` gets new random keys, which is plenty for
+/// jitter without pulling in a crate for it.
+fn random() -> f64 {
+    RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64
+}
+
+/// How the music source's connection is doing, for the control API
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connecting,
+    /// The server we're connected to, or the file or device we're reading from
+    Connected { source: String },
+    /// The source ran out of audio, like a file that plays once
+    Finished,
+    /// Waiting to try again after something went wrong
+    Retrying { attempt: usize, error: String, retry_in_ms: u64 },
+    /// We've given up, only a restart brings the music back
+    Failed { error: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_up_to_the_max() {
+        let config = ReconnectConfig {
+            jitter: 0.0,
+            ..ReconnectConfig::default()
+        };
+
+        assert_eq!(config.delay(1), Duration::from_secs(1));
+        assert_eq!(config.delay(2), Duration::from_secs(2));
+        assert_eq!(config.delay(4), Duration::from_secs(8));
+        assert_eq!(config.delay(100), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn healthy_after_delivering_for_a_while() {
+        let config = ReconnectConfig::default();
+        let now = Instant::now();
+
+        assert!(!config.was_healthy(None));
+        assert!(!config.was_healthy(Some(now)));
+        assert!(config.was_healthy(now.checked_sub(Duration::from_secs(11))));
+    }
+}
@@ -1,145 +1,424 @@
 use anyhow::{anyhow, Context, Result};
-use bytes::Buf;
-use claxon::{Block, FlacHeader};
-use futures::{pin_mut, stream::StreamExt};
+use futures::stream::StreamExt;
 use mac_address::get_mac_address;
-use mdns::RecordKind;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryInto;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use time::{Duration, Instant, NumericalDuration};
-use tokio::net::ToSocketAddrs;
+use tokio::net::{lookup_host, ToSocketAddrs};
+use tokio::time::{interval, Interval};
 use tokio_util::time::DelayQueue;
 
-use crate::controller::music::snap::protocol::{SnapHello, SnapKind, SnapMessage, SnapStream};
+use crate::controller::music::Frame;
+use crate::controller::music::snap::decoder::{self, AudioDecoder};
+use crate::controller::music::snap::mdns::{Discovery, RecordKind};
+#[cfg(any(test, feature = "mock"))]
+use crate::controller::music::snap::mock::MockConfig;
+use crate::controller::music::snap::protocol::{SnapClientInfo, SnapHello, SnapKind, SnapMessage, SnapStream};
+use crate::controller::music::source::FatalError;
 
 /// The MDNS service name that the snapserver uses
-const SERVICE_NAME: &'static str = "_snapcast._tcp.local";
+const SERVICE_NAME: &str = "_snapcast._tcp.local";
+/// The number of time requests we send right after connecting to get a decent estimate quickly
+const INITIAL_TIME_SYNCS: usize = 10;
+/// How often we ask the server for the time after the initial burst, the same as the official client
+const TIME_SYNC_PERIOD: std::time::Duration = std::time::Duration::from_secs(1);
+/// The number of time measurements we take the median of
+const TIME_SYNC_WINDOW: usize = 100;
+/// How early a frame can come out of the queue before we put it back in
+const MAX_EARLY: Duration = Duration::milliseconds(1);
+/// The port the snapserver streams on unless it's configured otherwise
+const DEFAULT_PORT: u16 = 1704;
+/// What the official client sends when it can't find a MAC address
+const UNKNOWN_MAC: &str = "00:00:00:00:00:00";
+
+/// How we introduce ourselves to the snapserver
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapConfig {
+    /// Snapweb shows clients by host name, defaults to the real host name
+    pub host_name: Option<String>,
+    pub client_name: String,
+    /// Tells apart several clients on the same host, starting at 1
+    pub instance: usize,
+    /// The ID the server remembers this client's settings by, defaults to the MAC address
+    pub id: Option<String>,
+    /// The port of the server's JSON-RPC API, which tells us when our stream starts and stops.
+    /// Disabled when unset.
+    pub rpc_port: Option<u16>,
+    /// Start a fake server in the background and connect to it instead of a real one, when built
+    /// with the `mock` feature
+    #[cfg(any(test, feature = "mock"))]
+    pub mock: Option<MockConfig>,
+    /// A server to connect to instead of discovering one, like "192.168.1.10:1704" or "music.local".
+    /// The port defaults to 1704.
+    pub server: Option<String>,
+    /// Discover a server when the configured one can't be reached
+    pub fallback_to_discovery: bool,
+    /// Only use discovered servers whose instance or host name contains this
+    pub discovery_filter: Option<String>,
+    /// Which kind of address to use for servers that have both
+    pub ip_version: IpVersion,
+}
+
+impl Default for SnapConfig {
+    fn default() -> Self {
+        SnapConfig {
+            host_name: None,
+            client_name: env!("CARGO_PKG_NAME").to_string(),
+            instance: 1,
+            id: None,
+            rpc_port: Some(1705),
+            #[cfg(any(test, feature = "mock"))]
+            mock: None,
+            server: None,
+            fallback_to_discovery: true,
+            discovery_filter: None,
+            ip_version: IpVersion::V4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpVersion {
+    /// Prefer IPv4 but use IPv6 if that's all there is
+    V4,
+    /// Prefer IPv6 but use IPv4 if that's all there is
+    V6,
+}
+
+impl IpVersion {
+    /// Pick the address to use out of several for the same server
+    fn pick<T: Copy>(self, addrs: &[T], ip: impl Fn(T) -> IpAddr) -> Option<T> {
+        let preferred = addrs.iter().copied().find(|&addr| {
+            matches!((self, ip(addr)), (IpVersion::V4, IpAddr::V4(_)) | (IpVersion::V6, IpAddr::V6(_)))
+        });
+
+        preferred.or_else(|| addrs.first().copied())
+    }
+}
+
+impl SnapConfig {
+    fn hello(&self) -> SnapHello {
+        let mac = match get_mac_address() {
+            Ok(Some(mac)) => mac.to_string(),
+            Ok(None) => {
+                log::warn!("No MAC address found, sending {} instead", UNKNOWN_MAC);
+                UNKNOWN_MAC.to_string()
+            }
+            Err(e) => {
+                log::warn!("Error getting the MAC address, sending {} instead: {}", UNKNOWN_MAC, e);
+                UNKNOWN_MAC.to_string()
+            }
+        };
+
+        let host_name = self.host_name.clone().unwrap_or_else(|| match hostname::get() {
+            Ok(name) => name.to_string_lossy().into_owned(),
+            Err(e) => {
+                log::warn!("Error getting the host name: {}", e);
+                "unknown".to_string()
+            }
+        });
+
+        // Without a MAC every client would end up with the same ID, the host name is more likely unique
+        let id = self.id.clone().unwrap_or_else(|| if mac != UNKNOWN_MAC { mac.clone() } else { host_name.clone() });
+
+        SnapHello {
+            arch: std::env::consts::ARCH.to_string(),
+            client_name: self.client_name.clone(),
+            host_name,
+            id,
+            instance: self.instance,
+            mac,
+            os: os_name(),
+            protocol_version: 2,
+            version: "0.17.1".to_string(),
+        }
+    }
+}
+
+/// Look up a "host:port" or just a "host" on the default port
+async fn resolve(server: &str, ip_version: IpVersion) -> Result<SocketAddr> {
+    let addrs: Vec<SocketAddr> = match lookup_host(server).await {
+        Ok(addrs) => addrs.collect(),
+        Err(_) => lookup_host((server, DEFAULT_PORT))
+            .await
+            .with_context(|| format!("Error looking up {}", server))?
+            .collect(),
+    };
+
+    ip_version
+        .pick(&addrs, |addr| addr.ip())
+        .ok_or_else(|| anyhow!("{} doesn't have any addresses", server))
+}
+
+/// The name of the OS, like "Raspbian GNU/Linux 10 (buster)"
+fn os_name() -> String {
+    let pretty_name = std::fs::read_to_string("/etc/os-release").ok().and_then(|release| {
+        release
+            .lines()
+            .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+            .map(|name| name.trim_matches('"').to_string())
+    });
+
+    pretty_name.unwrap_or_else(|| std::env::consts::OS.to_string())
+}
 
 pub struct SnapClient {
     /// The actual stream of messages coming in
     stream: SnapStream,
     /// Queue for raw audio frames
-    queue: DelayQueue<Vec<i32>>,
+    queue: DelayQueue<QueuedFrame>,
     /// Base timestamp from which all other timestamps are derived
     instant: Instant,
     /// Difference in time between the client and server
     time_diff: Duration,
+    /// The most recent measurements of the time difference
+    time_diffs: VecDeque<Duration>,
+    /// When to ask the server for the time again
+    time_sync: Interval,
     /// The amount of time to wait after the timestamp before playing a frame
     delay: Duration,
-    /// The codec header
-    header: Option<FlacHeader>,
+    /// The client's volume, between 0 and 1
+    volume: f64,
+    muted: bool,
+    /// The volume and mute state we last told the server about, unset until the first settings
+    reported: Option<SnapClientInfo>,
+    /// Decodes frames with the codec from the most recent codec header
+    decoder: Option<Box<dyn AudioDecoder>>,
+    /// The ID the server knows us by
+    id: String,
+    /// The most recent stream tags that nobody has picked up yet
+    tags: Option<HashMap<String, Value>>,
+}
+
+/// A frame waiting to be played
+struct QueuedFrame {
+    /// The server time at which the frame was recorded
+    timestamp: Duration,
+    frame: Frame,
 }
 
 impl SnapClient {
-    pub async fn discover() -> Result<SnapClient> {
-        // Iterate through responses from each Cast device, asking for new devices every 15s
-        let stream = mdns::discover::all(SERVICE_NAME, Duration::seconds(15).try_into()?)?.listen();
-        pin_mut!(stream);
+    /// Connect to the configured server, or discover one if there isn't one
+    pub async fn start(config: &SnapConfig) -> Result<SnapClient> {
+        let server = match &config.server {
+            Some(server) => server,
+            None => return SnapClient::discover(config).await,
+        };
+
+        let result = async {
+            let addr = resolve(server, config.ip_version).await?;
+            log::info!("Connecting to configured server {} at {}", server, addr);
 
-        while let Some(Ok(response)) = stream.next().await {
-            let mut addr: Option<IpAddr> = None;
+            SnapClient::connect(addr, config).await
+        };
+
+        match result.await {
+            Err(e) if config.fallback_to_discovery => {
+                log::warn!("Error connecting to {}, discovering a server instead: {:#}", server, e);
+                SnapClient::discover(config).await
+            }
+            result => result,
+        }
+    }
+
+    pub async fn discover(config: &SnapConfig) -> Result<SnapClient> {
+        // Go through the responses from each server, asking for new servers every 15s
+        let mut discovery = Discovery::new(SERVICE_NAME, Duration::seconds(15).try_into()?).await?;
+
+        loop {
+            let records = discovery.next().await.context("Error discovering a snapserver")?;
+
+            let mut addrs: Vec<IpAddr> = Vec::new();
             let mut port: Option<u16> = None;
+            // The instance and host names
+            let mut names: Vec<&str> = Vec::new();
 
             log::info!("Got response");
 
-            for record in response.records() {
-                match record.kind {
-                    RecordKind::SRV { port: p, .. } => port = Some(p),
-                    // We prefer A records because they're ipv4 addresses
-                    RecordKind::AAAA(ip) => addr = addr.or(Some(ip.into())),
-                    RecordKind::A(ip) => addr = Some(ip.into()),
+            for record in &records {
+                match &record.kind {
+                    RecordKind::Srv { port: p, target } => {
+                        port = Some(*p);
+                        names.push(&record.name);
+                        names.push(target);
+                    }
+                    RecordKind::Aaaa(ip) => addrs.push((*ip).into()),
+                    RecordKind::A(ip) => addrs.push((*ip).into()),
                     _ => (),
                 }
             }
 
+            if let Some(filter) = &config.discovery_filter {
+                let filter = filter.to_lowercase();
+
+                if !names.iter().any(|name| name.to_lowercase().contains(&filter)) {
+                    log::info!("Skipping server {:?} because it doesn't match {}", names, filter);
+                    continue;
+                }
+            }
+
+            let addr = config.ip_version.pick(&addrs, |ip| ip);
+
             if let (Some(addr), Some(port)) = (addr, port) {
                 log::info!("Got addr {} and port {} from response", addr, port);
-                return SnapClient::connect((addr, port)).await;
+                return SnapClient::connect((addr, port), config).await;
             } else {
                 log::info!("Failed to find addr and port from response");
             }
         }
-
-        Err(anyhow!("Discovery stopped before we found a suitable server"))
     }
 
-    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<SnapClient> {
+    pub async fn connect<A: ToSocketAddrs>(addr: A, config: &SnapConfig) -> Result<SnapClient> {
         let instant = Instant::now();
         let mut stream = SnapStream::connect(addr, instant).await?;
 
-        // TODO fill this out with the correct information
-        let hello = SnapKind::Hello {
-            payload: SnapHello {
-                arch: "x86_64".to_string(),
-                client_name: "Snapclient".to_string(),
-                host_name: "hostname".to_string(),
-                id: get_mac_address().unwrap().unwrap().to_string(), // TODO
-                instance: 1,
-                mac: get_mac_address().unwrap().unwrap().to_string(), // TODO
-                os: "Ubuntu".to_string(),
-                protocol_version: 2,
-                version: "0.17.1".to_string(),
-            },
+        let hello = config.hello();
+
+        // The server tells apart instances on the same host by adding the instance to the ID
+        let id = match hello.instance {
+            1 => hello.id.clone(),
+            instance => format!("{}#{}", hello.id, instance),
         };
 
+        let hello = SnapKind::Hello { payload: hello };
+
         stream.send(hello).await?;
 
-        let time = SnapKind::Time {
-            delta: Duration::new(0, 0),
-        };
+        for _ in 0..INITIAL_TIME_SYNCS {
+            let time = SnapKind::Time {
+                delta: Duration::new(0, 0),
+            };
 
-        stream.send(time).await?;
+            stream.send(time).await?;
+        }
 
-        return Ok(SnapClient {
-            stream: stream,
+        Ok(SnapClient {
+            stream,
             queue: DelayQueue::new(),
-            instant: instant,
+            instant,
 
             // TODO maybe just wait for the necessary information here rather than initializing with fake data
             delay: Duration::new(0, 0),
+            volume: 1.0,
+            muted: false,
+            reported: None,
             time_diff: Duration::new(0, 0),
-            header: None,
-        });
+            time_diffs: VecDeque::with_capacity(TIME_SYNC_WINDOW),
+            time_sync: interval(TIME_SYNC_PERIOD),
+            decoder: None,
+            id,
+            tags: None,
+        })
     }
 
-    pub async fn next(&mut self) -> Result<Option<Vec<i32>>> {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Tags describing what's playing, if they changed since the last call
+    pub fn take_tags(&mut self) -> Option<HashMap<String, Value>> {
+        self.tags.take()
+    }
+
+    pub fn server_addr(&self) -> Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub async fn next(&mut self) -> Result<Option<Frame>> {
         loop {
             tokio::select! {
                 // New messages from the snapserver
-                Some(msg) = self.stream.next() => {
-                    let msg = msg.context("Error while retrieving message from stream")?;
+                msg = self.stream.next() => {
+                    let msg = match msg {
+                        Some(msg) => msg.context("Error while retrieving message from stream")?,
+                        None => return Ok(None),
+                    };
 
                     self.process_message(msg).await.context("Error while processing SnapMessage")?;
                 },
                 // New frames to return to the caller
-                Some(frame) = self.queue.next() => {
-                    let frame = frame.context("Error while retrieving frame from queue")?;
+                Some(queued) = self.queue.next() => {
+                    let mut queued = queued.context("Error while retrieving frame from queue")?.into_inner();
+
+                    // Our estimate of the time difference may have changed since the frame was queued.
+                    // The queue only has millisecond precision so we tend to be a millisecond or so late.
+                    let until_play = self.until_play(queued.timestamp);
+                    queued.frame.timestamp = std::time::Instant::now() + until_play;
 
-                    // TODO we are consistently about a millisecond or two late...
-                    let time_over = frame.deadline().elapsed();
+                    if until_play > MAX_EARLY {
+                        self.queue.insert(queued, until_play.try_into()?);
+                        continue;
+                    }
 
-                    let frame = frame.into_inner();
+                    // The volume may have changed while the frame waited, it plays with the current one
+                    let mut frame = queued.frame;
+                    frame.volume = self.volume;
+                    frame.muted = self.muted;
 
                     // Make sure this frame isn't too old...
-                    let length = self.header.as_ref().map_or(
-                        20.milliseconds(),
-                        |header| (frame.len() as f64 / header.streaminfo().sample_rate as f64).seconds()
-                    );
+                    let length = (frame.num_samples() as f64 / frame.sample_rate as f64).seconds();
 
-                    if time_over < length {
+                    if -until_play < length {
                         return Ok(Some(frame))
                     }
                 },
-                else => return Ok(None),
+                // Keep our estimate of the time difference up to date
+                _ = self.time_sync.tick() => {
+                    let time = SnapKind::Time {
+                        delta: Duration::new(0, 0),
+                    };
+
+                    self.stream.send(time).await.context("Error while requesting the server time")?;
+                },
             }
         }
     }
 
+    /// The time until a frame with the given timestamp should be played, negative if it's late
+    fn until_play(&self, timestamp: Duration) -> Duration {
+        let server_now = self.instant.elapsed() + self.time_diff;
+
+        (timestamp - server_now) + self.delay
+    }
+
     async fn process_message(&mut self, msg: SnapMessage) -> Result<()> {
         match msg.kind {
             SnapKind::ServerSettings { settings } => {
-                // TODO are the other fields of any use here?
-                self.delay = settings.buffer_ms;
+                let volume = settings.volume.min(100) as f64 / 100.0;
+
+                // Let the server know what we're playing with, like the official client. Always after
+                // the first settings, then whenever they change.
+                let info = SnapClientInfo {
+                    volume: settings.volume.min(100),
+                    muted: settings.muted,
+                };
+
+                if self.reported.as_ref() != Some(&info) {
+                    self.stream
+                        .send(SnapKind::ClientInfo { info: info.clone() })
+                        .await
+                        .context("Error while sending client info")?;
+                    self.reported = Some(info);
+                }
+
+                // Like the official client, the latency configured for this client means playing
+                // frames that much earlier to make up for a slow audio output
+                self.delay = settings.buffer_ms - settings.latency;
+                self.volume = volume;
+                self.muted = settings.muted;
+
+                log::info!(
+                    "Server settings [buffer in ms: {}, latency in ms: {}, volume: {}, muted: {}]",
+                    settings.buffer_ms.whole_milliseconds(),
+                    settings.latency.whole_milliseconds(),
+                    settings.volume,
+                    settings.muted
+                );
 
                 Ok(())
             }
@@ -148,67 +427,69 @@ impl SnapClient {
             } => {
                 // This is like NTP
                 let server_to_client = msg.base.sent - msg.base.received;
-                self.time_diff = (client_to_server + server_to_client) / 2;
+
+                if self.time_diffs.len() == TIME_SYNC_WINDOW {
+                    self.time_diffs.pop_front();
+                }
+                self.time_diffs.push_back((client_to_server + server_to_client) / 2);
+
+                // The median ignores the occasional measurement thrown off by network jitter
+                let mut sorted: Vec<Duration> = self.time_diffs.iter().cloned().collect();
+                sorted.sort();
+                self.time_diff = sorted[sorted.len() / 2];
 
                 Ok(())
             }
             SnapKind::CodecHeader { codec, payload } => {
-                match codec.as_str() {
-                    "flac" => (),
-                    // TODO support the other codecs...
-                    s => return Err(anyhow!("The SnapServer is using an unsupported codec: {}", s)),
-                }
-
-                self.header = Some(FlacHeader::from_header(payload).context("Error reading FLAC header")?);
+                self.decoder = Some(decoder::from_header(&codec, payload)?);
 
                 Ok(())
             }
-            SnapKind::WireChunk { timestamp, mut payload } if self.header.is_some() => {
-                // TODO block makes an allocation
-                let block = Block::from_frame(&mut payload).context("Error reading FLAC block")?;
-
-                assert!(payload.remaining() == 0);
-
-                let data = if block.channels() != 1 {
-                    let num_channels = block.channels() as usize;
-                    let block_size = block.len() as usize / num_channels;
-
-                    let buffer = block.into_buffer();
-
-                    // Channels are stored sequentially, meaning the entire first channel is stored,
-                    // then the entire second channel, and so on.
-                    //
-                    // 0 1 2 3 4 5 6 7 8 0 1 2 3 4 5 6 7 8 ...
-                    // [   channel 1   ] [   channel 2   ] ...
-                    //
-                    // Here we are just taking the average of all the channels to produce one channel.
-
-                    (0..block_size)
-                        .map(|i| {
-                            (0..num_channels).map(|j| buffer[i + block_size * j]).sum::<i32>() as i32
-                                / num_channels as i32
-                        })
-                        .collect()
-                } else {
-                    // Small optimization, just return the block of data if it only has one channel.
-                    // TODO the frame might be guaranteed to have more than 1 channel...
-                    block.into_buffer()
+            SnapKind::WireChunk { timestamp, payload } => {
+                let decoder = match &mut self.decoder {
+                    Some(decoder) => decoder,
+                    None => return Ok(()),
                 };
 
+                let sample_rate = decoder.sample_rate();
+                let channels = decoder.decode(payload)?;
+
                 // Compute the delay before the frame should be 'played'. This is based on the server
                 // provided value of the amount of buffer time and the timestamp of the frame.
                 //
                 // We want the frame to play when the server time hits timestamp + delay.
 
-                let server_now = self.instant.elapsed() + self.time_diff;
-                let delay = (timestamp - server_now) + self.delay;
+                let delay = self.until_play(timestamp);
 
                 if delay.is_positive() {
-                    self.queue.insert(data, delay.try_into()?);
+                    let delay: std::time::Duration = delay.try_into()?;
+
+                    let frame = Frame {
+                        timestamp: std::time::Instant::now() + delay,
+                        sample_rate,
+                        channels,
+                        volume: self.volume,
+                        muted: self.muted,
+                    };
+                    self.queue.insert(QueuedFrame { timestamp, frame }, delay);
                 }
 
                 Ok(())
             }
+            SnapKind::StreamTags { tags } => {
+                self.tags = Some(tags);
+
+                Ok(())
+            }
+            SnapKind::Error { code, error, message } => {
+                let e = format!("The SnapServer sent error {}: {} ({})", code, error, message);
+
+                match code {
+                    // We aren't allowed in, and won't be until somebody changes the server's configuration
+                    401 | 403 => Err(FatalError(e).into()),
+                    _ => Err(anyhow!(e)),
+                }
+            }
             // TODO
             _ => Ok(()),
         }
@@ -0,0 +1,535 @@
+use anyhow::{anyhow, Context, Result};
+use bytes::{Buf, Bytes};
+use claxon::frame::FrameReader;
+use claxon::metadata::StreamInfo;
+use claxon::FlacReader;
+use lewton::audio::{read_audio_packet, PreviousWindowRight};
+use lewton::header::{read_header_ident, read_header_setup, IdentHeader, SetupHeader};
+use ogg::reading::{BasePacketReader, PageParser};
+use std::io::Cursor;
+
+use crate::controller::music::source::FatalError;
+
+/// The marker at the start of Snapcast's opus codec header, "OPUS" read as a little endian u32
+#[cfg(feature = "opus")]
+const OPUS_ID: u32 = 0x4F50_5553;
+/// The most samples per channel an opus packet can hold (120ms at 48kHz)
+#[cfg(feature = "opus")]
+const MAX_OPUS_SAMPLES: usize = 5760;
+/// The fixed part of an ogg page header, before the segment table
+const OGG_HEADER_SIZE: usize = 27;
+
+/// Turns the wire chunks from the snapserver into samples.
+///
+/// Samples are scaled to the range of a 16 bit integer no matter what the stream uses, so
+/// controllers don't have to care which codec the server is set to.
+pub trait AudioDecoder: Send {
+    fn sample_rate(&self) -> usize;
+
+    /// Decode a wire chunk into the samples for each channel
+    fn decode(&mut self, payload: Bytes) -> Result<Vec<Vec<i32>>>;
+}
+
+/// Create the right decoder for the codec header sent by the server
+pub fn from_header(codec: &str, payload: Bytes) -> Result<Box<dyn AudioDecoder>> {
+    let decoder: Box<dyn AudioDecoder> = match codec {
+        "flac" => Box::new(FlacDecoder::new(payload)?),
+        "pcm" => Box::new(PcmDecoder::new(payload)?),
+        #[cfg(feature = "opus")]
+        "opus" => Box::new(OpusDecoder::new(payload)?),
+        #[cfg(not(feature = "opus"))]
+        "opus" => {
+            return Err(FatalError("Opus support isn't built in, build with --features opus".to_string()).into())
+        }
+        "ogg" => Box::new(VorbisDecoder::new(payload)?),
+        s => return Err(FatalError(format!("The SnapServer is using an unsupported codec: {}", s)).into()),
+    };
+
+    log::info!("Decoding {} at {}Hz", codec, decoder.sample_rate());
+
+    Ok(decoder)
+}
+
+/// Shift samples with more than 16 bits down into the 16 bit range
+fn scale_to_16_bits(channels: &mut [Vec<i32>], bits: u32) {
+    if bits > 16 {
+        let shift = bits - 16;
+
+        for sample in channels.iter_mut().flat_map(|channel| channel.iter_mut()) {
+            *sample >>= shift;
+        }
+    }
+}
+
+/// The header is the start of a FLAC stream up to the first frame, every chunk is one frame
+struct FlacDecoder {
+    streaminfo: StreamInfo,
+}
+
+impl FlacDecoder {
+    fn new(payload: Bytes) -> Result<FlacDecoder> {
+        let streaminfo = FlacReader::new(Cursor::new(payload)).context("Error reading FLAC header")?.streaminfo();
+
+        Ok(FlacDecoder { streaminfo })
+    }
+}
+
+impl AudioDecoder for FlacDecoder {
+    fn sample_rate(&self) -> usize {
+        self.streaminfo.sample_rate as usize
+    }
+
+    fn decode(&mut self, payload: Bytes) -> Result<Vec<Vec<i32>>> {
+        let mut input = Cursor::new(payload);
+
+        // TODO block makes an allocation
+        let block = FrameReader::new(&mut input)
+            .read_next_or_eof(Vec::new())
+            .context("Error reading FLAC block")?
+            .ok_or_else(|| anyhow!("FLAC chunk is empty"))?;
+
+        // Snapcast sends one frame per chunk, anything after it means we split the frame wrong
+        let remaining = input.get_ref().len() - input.position() as usize;
+        if remaining > 0 {
+            return Err(anyhow!("FLAC chunk has {} bytes left over after the frame", remaining));
+        }
+
+        let num_channels = block.channels() as usize;
+        let block_size = block.len() as usize / num_channels;
+
+        // Channels are stored sequentially, meaning the entire first channel is stored,
+        // then the entire second channel, and so on.
+        //
+        // 0 1 2 3 4 5 6 7 8 0 1 2 3 4 5 6 7 8 ...
+        // [   channel 1   ] [   channel 2   ] ...
+        //
+        // The channels are kept separate, it's up to the controller to mix them.
+
+        let mut channels = if num_channels != 1 {
+            block
+                .into_buffer()
+                .chunks(block_size.max(1))
+                .take(num_channels)
+                .map(|channel| channel.to_vec())
+                .collect()
+        } else {
+            // Small optimization, just return the block of data if it only has one channel.
+            vec![block.into_buffer()]
+        };
+
+        scale_to_16_bits(&mut channels, self.streaminfo.bits_per_sample);
+
+        Ok(channels)
+    }
+}
+
+/// Raw interleaved little endian samples, the header is a WAV header
+struct PcmDecoder {
+    sample_rate: usize,
+    num_channels: usize,
+    bits: u32,
+}
+
+impl PcmDecoder {
+    fn new(mut payload: Bytes) -> Result<PcmDecoder> {
+        if payload.len() < 12 || &payload[0..4] != b"RIFF" || &payload[8..12] != b"WAVE" {
+            return Err(anyhow!("PCM header isn't a WAV header"));
+        }
+        payload.advance(12);
+
+        // Look through the chunks for the format
+        while payload.remaining() >= 8 {
+            let id = payload.split_to(4);
+            let size = payload.get_u32_le() as usize;
+
+            if size > payload.remaining() {
+                break;
+            }
+
+            let mut chunk = payload.split_to(size);
+
+            if &id[..] == b"fmt " && size >= 16 {
+                let _format = chunk.get_u16_le();
+                let num_channels = chunk.get_u16_le() as usize;
+                let sample_rate = chunk.get_u32_le() as usize;
+                let _byte_rate = chunk.get_u32_le();
+                let _block_align = chunk.get_u16_le();
+                let bits = chunk.get_u16_le() as u32;
+
+                if num_channels == 0 || !matches!(bits, 8 | 16 | 24 | 32) {
+                    return Err(anyhow!("Unsupported PCM format: {} channels of {} bits", num_channels, bits));
+                }
+
+                return Ok(PcmDecoder {
+                    sample_rate,
+                    num_channels,
+                    bits,
+                });
+            }
+        }
+
+        Err(anyhow!("PCM header is missing the format chunk"))
+    }
+
+    /// The number of bytes each sample takes up, Snapcast pads 24 bit samples to 4 bytes
+    fn sample_size(&self) -> usize {
+        match self.bits {
+            24 => 4,
+            bits => bits as usize / 8,
+        }
+    }
+}
+
+impl AudioDecoder for PcmDecoder {
+    fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    fn decode(&mut self, payload: Bytes) -> Result<Vec<Vec<i32>>> {
+        let sample_size = self.sample_size();
+        let frame_size = sample_size * self.num_channels;
+
+        if !payload.len().is_multiple_of(frame_size) {
+            return Err(anyhow!("PCM chunk of {} bytes isn't a whole number of frames", payload.len()));
+        }
+
+        let num_samples = payload.len() / frame_size;
+        let mut channels = vec![Vec::with_capacity(num_samples); self.num_channels];
+
+        for frame in payload.chunks_exact(frame_size) {
+            for (channel, mut sample) in channels.iter_mut().zip(frame.chunks_exact(sample_size)) {
+                let value = match sample_size {
+                    // 8 bit WAV samples are unsigned
+                    1 => (sample.get_u8() as i32 - 128) << 8,
+                    2 => sample.get_i16_le() as i32,
+                    _ => sample.get_i32_le(),
+                };
+
+                channel.push(value);
+            }
+        }
+
+        if sample_size > 2 {
+            scale_to_16_bits(&mut channels, self.bits);
+        }
+
+        Ok(channels)
+    }
+}
+
+#[cfg(feature = "opus")]
+struct OpusDecoder {
+    decoder: opus::Decoder,
+    sample_rate: usize,
+    num_channels: usize,
+    buf: Vec<i16>,
+}
+
+#[cfg(feature = "opus")]
+impl OpusDecoder {
+    /// Snapcast's opus header is the id followed by the sample rate, bits per sample and channels
+    fn new(mut payload: Bytes) -> Result<OpusDecoder> {
+        if payload.len() < 12 || payload.get_u32_le() != OPUS_ID {
+            return Err(anyhow!("Invalid opus header"));
+        }
+
+        let sample_rate = payload.get_u32_le();
+        let _bits = payload.get_u16_le();
+        let num_channels = payload.get_u16_le() as usize;
+
+        let channels = match num_channels {
+            1 => opus::Channels::Mono,
+            2 => opus::Channels::Stereo,
+            n => return Err(anyhow!("Opus doesn't support {} channels", n)),
+        };
+
+        let decoder = opus::Decoder::new(sample_rate, channels).context("Error creating opus decoder")?;
+
+        Ok(OpusDecoder {
+            decoder,
+            sample_rate: sample_rate as usize,
+            num_channels,
+            buf: vec![0; MAX_OPUS_SAMPLES * num_channels],
+        })
+    }
+}
+
+#[cfg(feature = "opus")]
+impl AudioDecoder for OpusDecoder {
+    fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    fn decode(&mut self, payload: Bytes) -> Result<Vec<Vec<i32>>> {
+        let num_samples = self
+            .decoder
+            .decode(&payload, &mut self.buf, false)
+            .context("Error decoding opus packet")?;
+
+        let mut channels = vec![Vec::with_capacity(num_samples); self.num_channels];
+
+        for frame in self.buf[..num_samples * self.num_channels].chunks_exact(self.num_channels) {
+            for (channel, &sample) in channels.iter_mut().zip(frame) {
+                channel.push(sample as i32);
+            }
+        }
+
+        Ok(channels)
+    }
+}
+
+/// Vorbis in an ogg container, the header holds the pages with the three vorbis headers.
+///
+/// Every chunk holds the pages that follow, so one packet reader follows the stream from the header
+/// on. Ogg only lets a stream start on its first page, and packets can carry on into the next chunk.
+struct VorbisDecoder {
+    packets: BasePacketReader,
+    ident: IdentHeader,
+    setup: SetupHeader,
+    previous_window: PreviousWindowRight,
+}
+
+impl VorbisDecoder {
+    fn new(payload: Bytes) -> Result<VorbisDecoder> {
+        let mut packets = BasePacketReader::new();
+        let mut headers = read_packets(&mut packets, payload).context("Error reading ogg header pages")?.into_iter();
+
+        let mut next_packet = || headers.next().ok_or_else(|| anyhow!("Ogg header is missing vorbis headers"));
+
+        let ident = read_header_ident(&next_packet()?).context("Error reading vorbis ident header")?;
+        // We don't need the comments, stream tags come in their own message
+        let _comment = next_packet()?;
+        let setup = read_header_setup(&next_packet()?, ident.audio_channels, (ident.blocksize_0, ident.blocksize_1))
+            .context("Error reading vorbis setup header")?;
+
+        Ok(VorbisDecoder {
+            packets,
+            ident,
+            setup,
+            previous_window: PreviousWindowRight::new(),
+        })
+    }
+}
+
+impl AudioDecoder for VorbisDecoder {
+    fn sample_rate(&self) -> usize {
+        self.ident.audio_sample_rate as usize
+    }
+
+    fn decode(&mut self, payload: Bytes) -> Result<Vec<Vec<i32>>> {
+        let mut channels = vec![Vec::new(); self.ident.audio_channels as usize];
+
+        // A chunk can hold several pages, each with several packets
+        for packet in read_packets(&mut self.packets, payload).context("Error reading ogg page")? {
+            let decoded = read_audio_packet(&self.ident, &self.setup, &packet, &mut self.previous_window)
+                .context("Error decoding vorbis packet")?;
+
+            for (channel, samples) in channels.iter_mut().zip(decoded) {
+                channel.extend(samples.into_iter().map(|sample| sample as i32));
+            }
+        }
+
+        Ok(channels)
+    }
+}
+
+/// Read the packets that the ogg pages in the payload finish. The reader only holds one page at a
+/// time, so its packets have to be read before the next page goes in.
+fn read_packets(packets: &mut BasePacketReader, mut payload: Bytes) -> Result<Vec<Vec<u8>>> {
+    let mut data = Vec::new();
+
+    while payload.has_remaining() {
+        if payload.remaining() < OGG_HEADER_SIZE {
+            return Err(anyhow!("Truncated ogg page header"));
+        }
+
+        let mut header = [0; OGG_HEADER_SIZE];
+        payload.copy_to_slice(&mut header);
+
+        let (mut parser, segments_size) = PageParser::new(header)?;
+        if payload.remaining() < segments_size {
+            return Err(anyhow!("Truncated ogg segment table"));
+        }
+
+        let body_size = parser.parse_segments(payload.split_to(segments_size).to_vec());
+        if payload.remaining() < body_size {
+            return Err(anyhow!("Truncated ogg page"));
+        }
+
+        let page = parser.parse_packet_data(payload.split_to(body_size).to_vec())?;
+        packets.push_page(page)?;
+
+        while let Some(packet) = packets.read_packet() {
+            data.push(packet.data);
+        }
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// The FLAC and Vorbis payloads are cut from the 440Hz sine samples in the audrey crate, the
+    /// opus payload is a 440Hz sine on the left and 1kHz on the right encoded with libopus
+    fn testdata(name: &str) -> Bytes {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name);
+
+        std::fs::read(&path).unwrap_or_else(|e| panic!("Error reading {}: {}", path.display(), e)).into()
+    }
+
+    fn wav_header(channels: u16, sample_rate: u32, bits: u16) -> Bytes {
+        let mut header = Vec::new();
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&36u32.to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * channels as u32 * bits as u32 / 8).to_le_bytes());
+        header.extend_from_slice(&(channels * bits / 8).to_le_bytes());
+        header.extend_from_slice(&bits.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.into()
+    }
+
+    /// The frequency of a sine wave, from how often it crosses zero
+    fn frequency(samples: &[i32], sample_rate: usize) -> f64 {
+        let crossings = samples.windows(2).filter(|w| (w[0] < 0) != (w[1] < 0)).count();
+
+        crossings as f64 / 2.0 / (samples.len() as f64 / sample_rate as f64)
+    }
+
+    fn rms(samples: &[i32]) -> f64 {
+        (samples.iter().map(|&s| (s as f64).powi(2)).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn pcm_16_bit() {
+        let mut decoder = from_header("pcm", wav_header(2, 48000, 16)).unwrap();
+        assert_eq!(decoder.sample_rate(), 48000);
+
+        let chunk: Vec<u8> = [1i16, -1, 32767, -32768, 0, 300].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let channels = decoder.decode(chunk.into()).unwrap();
+
+        assert_eq!(channels, vec![vec![1, 32767, 0], vec![-1, -32768, 300]]);
+    }
+
+    #[test]
+    fn pcm_24_bit_is_scaled() {
+        let mut decoder = from_header("pcm", wav_header(1, 44100, 24)).unwrap();
+
+        // Snapcast pads 24 bit samples to 4 bytes
+        let chunk: Vec<u8> = [0x7F_FFFFi32, -0x80_0000, 0x100].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let channels = decoder.decode(chunk.into()).unwrap();
+
+        assert_eq!(channels, vec![vec![32767, -32768, 1]]);
+    }
+
+    #[test]
+    fn pcm_32_bit_is_scaled() {
+        let mut decoder = from_header("pcm", wav_header(1, 44100, 32)).unwrap();
+
+        let chunk: Vec<u8> = [i32::MAX, i32::MIN, 0x1_0000].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let channels = decoder.decode(chunk.into()).unwrap();
+
+        // Full scale stays full scale, whatever the width
+        assert_eq!(channels, vec![vec![i16::MAX as i32, i16::MIN as i32, 1]]);
+    }
+
+    #[test]
+    fn pcm_8_bit_is_unsigned() {
+        let mut decoder = from_header("pcm", wav_header(1, 8000, 8)).unwrap();
+
+        let channels = decoder.decode(Bytes::from_static(&[0, 128, 255])).unwrap();
+
+        assert_eq!(channels, vec![vec![-32768, 0, 32512]]);
+    }
+
+    #[test]
+    fn pcm_partial_frame() {
+        let mut decoder = from_header("pcm", wav_header(2, 48000, 16)).unwrap();
+
+        assert!(decoder.decode(Bytes::from_static(&[0, 0, 0])).is_err());
+    }
+
+    #[test]
+    fn pcm_invalid_header() {
+        assert!(from_header("pcm", Bytes::from_static(b"RIFF\0\0\0\0WAVE")).is_err());
+        assert!(from_header("pcm", wav_header(2, 48000, 12)).is_err());
+    }
+
+    #[test]
+    fn flac() {
+        let mut decoder = from_header("flac", testdata("flac.header")).unwrap();
+        assert_eq!(decoder.sample_rate(), 44100);
+
+        let channels = decoder.decode(testdata("flac.chunk")).unwrap();
+
+        assert_eq!(channels.len(), 2);
+        for channel in &channels {
+            assert_eq!(channel.len(), 4096);
+            // The start of the sine, straight from the WAV it was encoded from
+            assert_eq!(channel[..8], [0, 1451, 2900, 4337, 5756, 7152, 8521, 9856]);
+            assert_eq!(channel[4090..], [-21714, -21159, -20522, -19804, -19008, -18138]);
+        }
+    }
+
+    #[test]
+    fn flac_trailing_bytes() {
+        let mut decoder = from_header("flac", testdata("flac.header")).unwrap();
+
+        let mut chunk = testdata("flac.chunk").to_vec();
+        chunk.extend_from_slice(&[0, 0]);
+
+        assert!(decoder.decode(chunk.into()).is_err());
+    }
+
+    #[test]
+    fn vorbis() {
+        let mut decoder = from_header("ogg", testdata("ogg.header")).unwrap();
+        assert_eq!(decoder.sample_rate(), 44100);
+
+        let channels = decoder.decode(testdata("ogg.chunk")).unwrap();
+
+        assert_eq!(channels.len(), 2);
+        for channel in &channels {
+            // Everything up to the granule position of the page
+            assert_eq!(channel.len(), 17984);
+            // Vorbis is lossy, so only check that it sounds about right. This sine is at full scale.
+            assert!((frequency(channel, 44100) - 440.0).abs() < 10.0);
+            assert!((rms(channel) - i16::MAX as f64 / 2f64.sqrt()).abs() < 1000.0);
+        }
+    }
+
+    #[cfg(feature = "opus")]
+    #[test]
+    fn opus() {
+        let mut decoder = from_header("opus", testdata("opus.header")).unwrap();
+        assert_eq!(decoder.sample_rate(), 48000);
+
+        let channels = decoder.decode(testdata("opus.chunk")).unwrap();
+
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels[0].len(), 960);
+        assert_eq!(channels[1].len(), 960);
+
+        // Opus is lossy, so only check that it sounds about right
+        assert!((frequency(&channels[0], 48000) - 440.0).abs() < 60.0);
+        assert!((frequency(&channels[1], 48000) - 1000.0).abs() < 60.0);
+        assert!((rms(&channels[0]) - 16384.0 / 2f64.sqrt()).abs() < 1500.0);
+        assert!((rms(&channels[1]) - 8192.0 / 2f64.sqrt()).abs() < 1000.0);
+    }
+
+    #[test]
+    fn unsupported_codec() {
+        let error = from_header("mp3", Bytes::new()).err().unwrap();
+
+        assert!(error.downcast_ref::<FatalError>().is_some());
+    }
+}
@@ -0,0 +1,63 @@
+//! The test tone the mock server plays when it isn't given a file.
+
+use anyhow::Result;
+use bytes::{BufMut, BytesMut};
+use std::f64::consts::PI;
+use std::path::Path;
+
+use crate::controller::music::audio_file::AudioFile;
+
+const TONE_SAMPLE_RATE: usize = 48000;
+/// The test tone loops after this many seconds
+const TONE_SECONDS: usize = 2;
+
+/// Load a WAV or FLAC file, or the test tone when there's no file
+pub fn load(path: Option<&Path>) -> Result<AudioFile> {
+    match path {
+        Some(path) => AudioFile::open(path),
+        None => Ok(tone()),
+    }
+}
+
+/// A bass, mid and treble tone, each fading in and out at a different speed so the lights move
+fn tone() -> AudioFile {
+    let tones = [(100.0, 0.5, 0.5), (1_000.0, 1.0, 0.3), (5_000.0, 2.0, 0.2)];
+
+    let num_samples = TONE_SAMPLE_RATE * TONE_SECONDS;
+    let mut samples = BytesMut::with_capacity(num_samples * 4);
+
+    for n in 0..num_samples {
+        let t = n as f64 / TONE_SAMPLE_RATE as f64;
+
+        let value: f64 = tones
+            .iter()
+            .map(|(freq, fade, volume)| {
+                (2.0 * PI * freq * t).sin() * (0.5 - 0.5 * (2.0 * PI * fade * t).cos()) * volume
+            })
+            .sum();
+
+        let sample = (value * i16::MAX as f64) as i16;
+
+        // Both channels
+        samples.put_i16_le(sample);
+        samples.put_i16_le(sample);
+    }
+
+    let mut header = BytesMut::with_capacity(44);
+    header.put_slice(b"RIFF");
+    header.put_u32_le(36 + samples.len() as u32);
+    header.put_slice(b"WAVE");
+    header.put_slice(b"fmt ");
+    header.put_u32_le(16);
+    header.put_u16_le(1); // PCM
+    header.put_u16_le(2);
+    header.put_u32_le(TONE_SAMPLE_RATE as u32);
+    header.put_u32_le(TONE_SAMPLE_RATE as u32 * 4);
+    header.put_u16_le(4);
+    header.put_u16_le(16);
+    header.put_slice(b"data");
+    header.put_u32_le(samples.len() as u32);
+
+    // The tone can't be empty
+    AudioFile::pcm(header.freeze(), samples.freeze(), TONE_SAMPLE_RATE, 4).unwrap()
+}
@@ -0,0 +1,170 @@
+//! Just enough mDNS to find a snapserver. Queries go out from an ordinary port, which makes
+//! responders answer us directly (a "legacy unicast" query, RFC 6762 section 6.7), so we don't
+//! have to share port 5353 with avahi or join the multicast group.
+
+use anyhow::{anyhow, bail, Context, Result};
+use dns_parser::{Builder, Packet, QueryClass, QueryType, RData};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{interval, Interval};
+
+/// Where mDNS queries go
+const MDNS_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+/// Responses have to fit in a single datagram, which is at most this big on any sane network
+const MAX_PACKET_SIZE: usize = 9000;
+
+/// The parts of a DNS record we look at
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub kind: RecordKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordKind {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Srv { port: u16, target: String },
+    Other,
+}
+
+/// Asks for a service every so often and hands out the responses
+pub struct Discovery {
+    socket: UdpSocket,
+    query: Vec<u8>,
+    interval: Interval,
+    buf: Vec<u8>,
+}
+
+impl Discovery {
+    pub async fn new(service: &str, period: Duration) -> Result<Discovery> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .await
+            .context("Error binding the mDNS socket")?;
+
+        let mut query = Builder::new_query(0, false);
+        query.add_question(service, false, QueryType::PTR, QueryClass::IN);
+        let query = query.build().map_err(|_| anyhow!("The mDNS query for {} is too long", service))?;
+
+        Ok(Discovery {
+            socket,
+            query,
+            interval: interval(period),
+            buf: vec![0; MAX_PACKET_SIZE],
+        })
+    }
+
+    /// The records of the next response, asking again whenever the period is up
+    pub async fn next(&mut self) -> Result<Vec<Record>> {
+        loop {
+            tokio::select! {
+                _ = self.interval.tick() => {
+                    self.socket
+                        .send_to(&self.query, SocketAddr::from(MDNS_ADDR))
+                        .await
+                        .context("Error sending the mDNS query")?;
+                }
+                received = self.socket.recv_from(&mut self.buf) => {
+                    let (len, from) = received.context("Error receiving an mDNS response")?;
+
+                    match parse(&self.buf[..len]) {
+                        Ok(records) => return Ok(records),
+                        Err(e) => log::debug!("Ignoring mDNS packet from {}: {:#}", from, e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The answers and additional records of a response, between them they have the port and address
+fn parse(packet: &[u8]) -> Result<Vec<Record>> {
+    let packet = Packet::parse(packet).map_err(|e| anyhow!("Error parsing mDNS packet: {}", e))?;
+
+    if packet.header.query {
+        bail!("Expected a response, got a query");
+    }
+
+    let records = packet
+        .answers
+        .iter()
+        .chain(packet.additional.iter())
+        .map(|record| Record {
+            name: record.name.to_string(),
+            kind: match &record.data {
+                RData::A(a) => RecordKind::A(a.0),
+                RData::AAAA(aaaa) => RecordKind::Aaaa(aaaa.0),
+                RData::SRV(srv) => RecordKind::Srv {
+                    port: srv.port,
+                    target: srv.target.to_string(),
+                },
+                _ => RecordKind::Other,
+            },
+        })
+        .collect();
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A name in DNS wire format
+    fn name(name: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        for label in name.split('.') {
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+        out.push(0);
+        out
+    }
+
+    fn record(out: &mut Vec<u8>, owner: &str, kind: u16, data: &[u8]) {
+        out.extend(name(owner));
+        out.extend_from_slice(&kind.to_be_bytes());
+        // IN, with the cache flush bit mDNS responders set
+        out.extend_from_slice(&0x8001u16.to_be_bytes());
+        out.extend_from_slice(&120u32.to_be_bytes());
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(data);
+    }
+
+    #[test]
+    fn reads_a_response() {
+        // A response with one answer and two additional records
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 2];
+
+        let ptr = name("Snapcast._snapcast._tcp.local");
+        record(&mut packet, "_snapcast._tcp.local", 12, &ptr);
+
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend_from_slice(&1704u16.to_be_bytes());
+        srv.extend(name("music.local"));
+        record(&mut packet, "Snapcast._snapcast._tcp.local", 33, &srv);
+
+        record(&mut packet, "music.local", 1, &[192, 168, 1, 10]);
+
+        let records = parse(&packet).unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].kind, RecordKind::Other);
+        assert_eq!(
+            records[1],
+            Record {
+                name: "Snapcast._snapcast._tcp.local".to_string(),
+                kind: RecordKind::Srv {
+                    port: 1704,
+                    target: "music.local".to_string()
+                },
+            }
+        );
+        assert_eq!(records[2].kind, RecordKind::A(Ipv4Addr::new(192, 168, 1, 10)));
+
+        // Other clients' queries come in too
+        let query = Builder::new_query(0, false).build().unwrap();
+        assert!(parse(&query).is_err());
+    }
+}
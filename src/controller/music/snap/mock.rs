@@ -0,0 +1,166 @@
+//! A fake snapserver for trying out the whole music pipeline without a real server.
+//!
+//! It streams a WAV or FLAC file (or a test tone) on a loop, answers time requests and sends the
+//! configured server settings, just like a snapserver with a single stream would.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use time::{Duration, Instant, NumericalDuration};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::sleep_until;
+
+use crate::controller::music::audio_file::AudioFile;
+use crate::controller::music::snap::fixture;
+use crate::controller::music::snap::protocol::{SnapKind, SnapServerSettings, SnapStream};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MockConfig {
+    /// Where the fake server listens, we connect to it instead of discovering a server
+    pub address: String,
+    /// A WAV or FLAC file to stream on a loop, a test tone is streamed when unset
+    pub file: Option<PathBuf>,
+    pub buffer_ms: i64,
+    pub latency_ms: i64,
+    /// Between 0 and 100
+    pub volume: usize,
+    pub muted: bool,
+    /// Sent as the stream's tags, like the title and artist of what's playing
+    pub tags: HashMap<String, Value>,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        MockConfig {
+            address: "127.0.0.1:1704".to_string(),
+            file: None,
+            buffer_ms: 1000,
+            latency_ms: 0,
+            volume: 100,
+            muted: false,
+            tags: HashMap::new(),
+        }
+    }
+}
+
+/// Start listening, clients are served in the background. Returns the address we're listening on,
+/// which is how to find the port when the configured one is 0.
+pub async fn start(config: MockConfig) -> Result<SocketAddr> {
+    let fixture = Arc::new(fixture::load(config.file.as_deref())?);
+
+    let listener = TcpListener::bind(&config.address)
+        .await
+        .with_context(|| format!("Error binding the mock snapserver to {}", config.address))?;
+    let addr = listener.local_addr()?;
+
+    log::info!(
+        "Mock snapserver streaming {} on {}",
+        config.file.as_ref().map_or("a test tone".to_string(), |file| file.display().to_string()),
+        addr
+    );
+
+    // The server's clock
+    let instant = Instant::now();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::error!("Mock snapserver stopped accepting connections: {}", e);
+                    return;
+                }
+            };
+
+            let config = config.clone();
+            let fixture = fixture.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, config, fixture, instant).await {
+                    log::error!("Mock snapserver error with {}: {}", peer, e);
+                }
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+async fn serve(stream: TcpStream, config: MockConfig, fixture: Arc<AudioFile>, instant: Instant) -> Result<()> {
+    let mut stream = SnapStream::new(stream, instant);
+
+    // Like the real server, wait for the client to introduce itself
+    loop {
+        match stream.next().await {
+            Some(msg) => {
+                if let SnapKind::Hello { payload } = msg?.kind {
+                    log::info!("Mock snapserver got a hello from {}", payload.host_name);
+                    break;
+                }
+            }
+            None => return Ok(()),
+        }
+    }
+
+    let settings = SnapServerSettings {
+        buffer_ms: config.buffer_ms.milliseconds(),
+        latency: config.latency_ms.milliseconds(),
+        muted: config.muted,
+        volume: config.volume,
+    };
+
+    stream.send(SnapKind::ServerSettings { settings }).await?;
+    stream
+        .send(SnapKind::CodecHeader {
+            codec: fixture.codec.to_string(),
+            payload: fixture.header.clone(),
+        })
+        .await?;
+
+    if !config.tags.is_empty() {
+        stream.send(SnapKind::StreamTags { tags: config.tags.clone() }).await?;
+    }
+
+    let start = tokio::time::Instant::now();
+    let start_timestamp = instant.elapsed();
+    // How much audio we've sent so far
+    let mut position = Duration::zero();
+
+    for (payload, length) in fixture.chunks.iter().cycle() {
+        let due = start + position.try_into()?;
+
+        // Answer time requests until it's time for the next chunk
+        loop {
+            tokio::select! {
+                msg = stream.next() => {
+                    let msg = match msg {
+                        Some(msg) => msg?,
+                        None => return Ok(()),
+                    };
+
+                    if let SnapKind::Time { .. } = msg.kind {
+                        let delta = msg.base.received - msg.base.sent;
+                        stream.send(SnapKind::Time { delta }).await?;
+                    }
+                },
+                _ = sleep_until(due) => break,
+            }
+        }
+
+        let chunk = SnapKind::WireChunk {
+            timestamp: start_timestamp + position,
+            payload: payload.clone(),
+        };
+
+        stream.send(chunk).await?;
+        position += *length;
+    }
+
+    Ok(())
+}
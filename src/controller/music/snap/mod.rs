@@ -1,2 +1,10 @@
 pub mod client;
-mod protocol;
\ No newline at end of file
+pub mod decoder;
+#[cfg(any(test, feature = "mock"))]
+pub mod fixture;
+mod mdns;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+mod protocol;
+pub mod rpc;
+pub mod source;
@@ -1,10 +1,13 @@
-use anyhow::{anyhow, Context, Error, Result};
+use anyhow::{Error, Result};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::{sink::SinkExt, stream::StreamExt};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::fmt;
 use std::io::Cursor;
+use std::net::SocketAddr;
 use time::{Duration, Instant, NumericalDuration};
 use tokio::net::TcpStream;
 use tokio::net::ToSocketAddrs;
@@ -12,6 +15,40 @@ use tokio_util::codec::Framed;
 use tokio_util::codec::{Decoder, Encoder};
 
 const BASE_MESSAGE_SIZE: usize = 26;
+/// The biggest message we accept, album art in the stream tags is the largest thing we expect
+const MAX_MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Why a message from the server couldn't be decoded
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// A field runs past the end of its message
+    Truncated { field: &'static str },
+    TooLarge { size: usize },
+    InvalidUtf8 { field: &'static str },
+    InvalidJson { field: &'static str, source: serde_json::Error },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated { field } => write!(f, "The {} runs past the end of the message", field),
+            ProtocolError::TooLarge { size } => {
+                write!(f, "Message of {} bytes is bigger than the limit of {} bytes", size, MAX_MESSAGE_SIZE)
+            }
+            ProtocolError::InvalidUtf8 { field } => write!(f, "The {} isn't valid UTF-8", field),
+            ProtocolError::InvalidJson { field, source } => write!(f, "Error while parsing {} JSON: {}", field, source),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::InvalidJson { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
 
 pub struct SnapStream {
     stream: Framed<TcpStream, SnapCodec>,
@@ -23,11 +60,15 @@ impl SnapStream {
     pub async fn connect<A: ToSocketAddrs>(addr: A, instant: Instant) -> Result<SnapStream> {
         let stream = TcpStream::connect(addr).await?;
 
-        Ok(SnapStream {
+        Ok(SnapStream::new(stream, instant))
+    }
+
+    pub fn new(stream: TcpStream, instant: Instant) -> SnapStream {
+        SnapStream {
             stream: Framed::new(stream, SnapCodec::new(instant)),
-            instant: instant,
+            instant,
             current_id: 0,
-        })
+        }
     }
 
     // TODO impl sink
@@ -38,12 +79,12 @@ impl SnapStream {
                 id: self.current_id,
                 refers_to: 0,
                 received: sent, // The recipient overwrites this field
-                sent: sent,
+                sent,
             },
             kind: msg,
         };
 
-        self.current_id += 1;
+        self.current_id = self.current_id.wrapping_add(1);
 
         self.stream.send(msg).await
     }
@@ -63,6 +104,10 @@ impl SnapStream {
     //     self.stream.send(msg).await
     // }
 
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.stream.get_ref().peer_addr()?)
+    }
+
     // TODO impl stream
     pub async fn next(&mut self) -> Option<Result<SnapMessage>> {
         self.stream.next().await
@@ -89,14 +134,16 @@ pub struct SnapBase {
     pub sent: Duration,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SnapKind {
     CodecHeader { codec: String, payload: Bytes },
     WireChunk { timestamp: Duration, payload: Bytes },
     ServerSettings { settings: SnapServerSettings },
     Time { delta: Duration },
     Hello { payload: SnapHello },
-    StreamTags { tags: HashMap<String, String> },
+    StreamTags { tags: HashMap<String, serde_json::Value> },
+    ClientInfo { info: SnapClientInfo },
+    Error { code: u32, error: String, message: String },
 }
 
 impl SnapKind {
@@ -108,6 +155,8 @@ impl SnapKind {
             SnapKind::Time { .. } => 4,
             SnapKind::Hello { .. } => 5,
             SnapKind::StreamTags { .. } => 6,
+            SnapKind::ClientInfo { .. } => 7,
+            SnapKind::Error { .. } => 8,
         }
     }
 
@@ -119,11 +168,13 @@ impl SnapKind {
             SnapKind::Time { .. } => 8,
             SnapKind::Hello { payload } => 4 + serde_json::to_vec(&payload).unwrap().len() as u32,
             SnapKind::StreamTags { tags } => 4 + serde_json::to_vec(&tags).unwrap().len() as u32,
+            SnapKind::ClientInfo { info } => 4 + serde_json::to_vec(&info).unwrap().len() as u32,
+            SnapKind::Error { error, message, .. } => 4 + 4 + error.len() as u32 + 4 + message.len() as u32,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SnapServerSettings {
     #[serde(deserialize_with = "deserialize_duration", serialize_with = "serialize_duration")]
@@ -134,6 +185,13 @@ pub struct SnapServerSettings {
     pub volume: usize,
 }
 
+/// The volume and mute state that the client is actually playing with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapClientInfo {
+    pub volume: usize,
+    pub muted: bool,
+}
+
 /// The durations are encoded as numbers of milliseconds.
 fn deserialize_duration<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
     Ok(Duration::milliseconds(Deserialize::deserialize(d)?))
@@ -144,7 +202,7 @@ fn serialize_duration<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Err
     s.serialize_i128(d.whole_milliseconds())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct SnapHello {
     pub arch: String,
@@ -177,119 +235,154 @@ impl Decoder for SnapCodec {
     type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<SnapMessage>, Error> {
-        if src.len() < BASE_MESSAGE_SIZE {
-            // We don't have a full base message yet
-            src.reserve(BASE_MESSAGE_SIZE);
-            return Ok(None);
-        }
-
-        // Create a cursor that wraps the source buffer so we can read the header
-        // without advancing the source buffers internal cursor.
-        let mut cursor = Cursor::new(&mut *src);
-
-        let msg_type = cursor.get_u16_le();
-        let id = cursor.get_u16_le();
-        let refers_to = cursor.get_u16_le();
-        let _received_sec = cursor.get_i32_le();
-        let _received_usec = cursor.get_i32_le();
-        let sent_sec = cursor.get_i32_le();
-        let sent_usec = cursor.get_i32_le();
-        let size = cursor.get_u32_le();
-
-        if src.len() < BASE_MESSAGE_SIZE + size as usize {
-            // We don't have the full message yet
-            src.reserve(BASE_MESSAGE_SIZE + size as usize);
-            return Ok(None);
-        }
-
-        let base = SnapBase {
-            id,
-            refers_to,
-            received: self.instant.elapsed(),
-            sent: sent_sec.seconds() + sent_usec.microseconds(),
-        };
-
-        // We successfully read the base message so move past it
-        src.advance(BASE_MESSAGE_SIZE);
-        // Cut out the message data from the source buffer
-        let mut data = src.split_to(size as usize);
-        // Reserve enough space for the next base message
-        src.reserve(BASE_MESSAGE_SIZE);
-
-        match msg_type {
-            1 => {
-                let codec_size = data.get_u32_le();
-                let codec = data.split_to(codec_size as usize);
-                let codec = std::str::from_utf8(&codec)?.to_string();
-
-                let size = data.get_u32_le();
-                let payload = data.split_to(size as usize).freeze();
-
-                Ok(Some(SnapMessage {
-                    base: base,
-                    kind: SnapKind::CodecHeader { codec, payload },
-                }))
+        // Newer servers may send messages we don't know about, the size lets us skip them
+        loop {
+            if src.len() < BASE_MESSAGE_SIZE {
+                // We don't have a full base message yet
+                src.reserve(BASE_MESSAGE_SIZE);
+                return Ok(None);
             }
-            2 => {
-                let timestamp_sec = data.get_i32_le();
-                let timestamp_usec = data.get_i32_le();
-                let size = data.get_u32_le();
-                let payload = data.split_to(size as usize).freeze();
-
-                Ok(Some(SnapMessage {
-                    base: base,
-                    kind: SnapKind::WireChunk {
-                        timestamp: timestamp_sec.seconds() + timestamp_usec.microseconds(),
-                        payload,
-                    },
-                }))
-            }
-            3 => {
-                let size = data.get_u32_le();
-                let payload = data.split_to(size as usize);
-                let settings = serde_json::from_slice(&payload).context("Error while parsing server settings JSON")?;
-
-                Ok(Some(SnapMessage {
-                    base: base,
-                    kind: SnapKind::ServerSettings { settings },
-                }))
-            }
-            4 => {
-                let latency_sec = data.get_i32_le();
-                let latency_usec = data.get_i32_le();
-
-                Ok(Some(SnapMessage {
-                    base: base,
-                    kind: SnapKind::Time {
-                        delta: latency_sec.seconds() + latency_usec.microseconds(),
-                    },
-                }))
+
+            // Create a cursor that wraps the source buffer so we can read the header
+            // without advancing the source buffers internal cursor.
+            let mut cursor = Cursor::new(&mut *src);
+
+            let msg_type = cursor.get_u16_le();
+            let id = cursor.get_u16_le();
+            let refers_to = cursor.get_u16_le();
+            let _received_sec = cursor.get_i32_le();
+            let _received_usec = cursor.get_i32_le();
+            let sent_sec = cursor.get_i32_le();
+            let sent_usec = cursor.get_i32_le();
+            let size = cursor.get_u32_le();
+
+            // Don't let a bogus size make us buffer forever
+            if size as usize > MAX_MESSAGE_SIZE {
+                return Err(ProtocolError::TooLarge { size: size as usize }.into());
             }
-            5 => {
-                let size = data.get_u32_le();
-                let payload = data.split_to(size as usize);
-                let payload = serde_json::from_slice(&payload).context("Error while parsing Hello JSON")?;
-
-                Ok(Some(SnapMessage {
-                    base: base,
-                    kind: SnapKind::Hello { payload },
-                }))
+
+            if src.len() < BASE_MESSAGE_SIZE + size as usize {
+                // We don't have the full message yet
+                src.reserve(BASE_MESSAGE_SIZE + size as usize);
+                return Ok(None);
             }
-            6 => {
-                let size = data.get_u32_le();
-                let payload = data.split_to(size as usize);
-                let tags = serde_json::from_slice(&payload).context("Error while parsing stream tags JSON")?;
-
-                Ok(Some(SnapMessage {
-                    base: base,
-                    kind: SnapKind::StreamTags { tags },
-                }))
+
+            let base = SnapBase {
+                id,
+                refers_to,
+                received: self.instant.elapsed(),
+                sent: sent_sec.seconds() + sent_usec.microseconds(),
+            };
+
+            // We successfully read the base message so move past it
+            src.advance(BASE_MESSAGE_SIZE);
+            // Cut out the message data from the source buffer
+            let mut data = src.split_to(size as usize);
+            // Reserve enough space for the next base message
+            src.reserve(BASE_MESSAGE_SIZE);
+
+            match decode_kind(msg_type, &mut data)? {
+                Some(kind) => return Ok(Some(SnapMessage { base, kind })),
+                None => log::debug!("Skipping message with unrecognized type {}", msg_type),
             }
-            id => Err(anyhow!("Unrecognized packet ID {}", id)),
         }
     }
 }
 
+/// Decode the body of a message, `None` if we don't know the message type
+fn decode_kind(msg_type: u16, data: &mut BytesMut) -> Result<Option<SnapKind>, ProtocolError> {
+    let kind = match msg_type {
+        1 => SnapKind::CodecHeader {
+            codec: get_string(data, "codec")?,
+            payload: get_bytes(data, "codec header payload")?.freeze(),
+        },
+        2 => SnapKind::WireChunk {
+            timestamp: get_time(data, "wire chunk timestamp")?,
+            payload: get_bytes(data, "wire chunk payload")?.freeze(),
+        },
+        3 => SnapKind::ServerSettings {
+            settings: get_json(data, "server settings")?,
+        },
+        4 => SnapKind::Time {
+            delta: get_time(data, "time delta")?,
+        },
+        5 => SnapKind::Hello {
+            payload: get_json(data, "hello")?,
+        },
+        6 => SnapKind::StreamTags {
+            tags: get_json(data, "stream tags")?,
+        },
+        7 => SnapKind::ClientInfo {
+            info: get_json(data, "client info")?,
+        },
+        8 => SnapKind::Error {
+            code: get_u32(data, "error code")?,
+            error: get_string(data, "error")?,
+            message: get_string(data, "error message")?,
+        },
+        _ => return Ok(None),
+    };
+
+    // Newer servers can add fields to the end of a message, which we don't need
+    if data.has_remaining() {
+        log::trace!("Ignoring {} bytes after message type {}", data.remaining(), msg_type);
+    }
+
+    Ok(Some(kind))
+}
+
+fn get_u32(data: &mut BytesMut, field: &'static str) -> Result<u32, ProtocolError> {
+    if data.remaining() < 4 {
+        return Err(ProtocolError::Truncated { field });
+    }
+
+    Ok(data.get_u32_le())
+}
+
+/// Times are seconds followed by microseconds
+fn get_time(data: &mut BytesMut, field: &'static str) -> Result<Duration, ProtocolError> {
+    if data.remaining() < 8 {
+        return Err(ProtocolError::Truncated { field });
+    }
+
+    let sec = data.get_i32_le();
+    let usec = data.get_i32_le();
+
+    Ok(sec.seconds() + usec.microseconds())
+}
+
+/// A u32 length followed by that many bytes
+fn get_bytes(data: &mut BytesMut, field: &'static str) -> Result<BytesMut, ProtocolError> {
+    let size = get_u32(data, field)? as usize;
+
+    if data.remaining() < size {
+        return Err(ProtocolError::Truncated { field });
+    }
+
+    Ok(data.split_to(size))
+}
+
+/// Strings are a u32 length followed by that many bytes of UTF-8
+fn get_string(data: &mut BytesMut, field: &'static str) -> Result<String, ProtocolError> {
+    let bytes = get_bytes(data, field)?;
+
+    Ok(std::str::from_utf8(&bytes).map_err(|_| ProtocolError::InvalidUtf8 { field })?.to_string())
+}
+
+/// JSON is sent just like strings
+fn get_json<T: DeserializeOwned>(data: &mut BytesMut, field: &'static str) -> Result<T, ProtocolError> {
+    let bytes = get_bytes(data, field)?;
+
+    serde_json::from_slice(&bytes).map_err(|source| ProtocolError::InvalidJson { field, source })
+}
+
+fn put_string(dst: &mut BytesMut, s: &str) -> Result<()> {
+    dst.put_u32_le(s.len().try_into()?);
+    dst.put_slice(s.as_bytes());
+
+    Ok(())
+}
+
 impl Encoder<SnapMessage> for SnapCodec {
     type Error = Error;
 
@@ -301,9 +394,9 @@ impl Encoder<SnapMessage> for SnapCodec {
         dst.put_u16_le(item.base.id);
         dst.put_u16_le(item.base.refers_to);
         dst.put_i32_le(item.base.received.whole_seconds().try_into()?);
-        dst.put_i32_le(item.base.received.subsec_microseconds().try_into()?);
+        dst.put_i32_le(item.base.received.subsec_microseconds());
         dst.put_i32_le(item.base.sent.whole_seconds().try_into()?);
-        dst.put_i32_le(item.base.sent.subsec_microseconds().try_into()?);
+        dst.put_i32_le(item.base.sent.subsec_microseconds());
         dst.put_u32_le(item.kind.size());
 
         match item.kind {
@@ -316,7 +409,7 @@ impl Encoder<SnapMessage> for SnapCodec {
             }
             SnapKind::WireChunk { timestamp, payload } => {
                 dst.put_i32_le(timestamp.whole_seconds().try_into()?);
-                dst.put_i32_le(timestamp.subsec_microseconds().try_into()?);
+                dst.put_i32_le(timestamp.subsec_microseconds());
                 dst.put_u32_le(payload.len() as u32);
                 dst.put_slice(&payload);
             }
@@ -327,7 +420,7 @@ impl Encoder<SnapMessage> for SnapCodec {
             }
             SnapKind::Time { delta } => {
                 dst.put_i32_le(delta.whole_seconds().try_into()?);
-                dst.put_i32_le(delta.subsec_microseconds().try_into()?);
+                dst.put_i32_le(delta.subsec_microseconds());
             }
             SnapKind::Hello { payload } => {
                 let payload = serde_json::to_vec(&payload)?;
@@ -339,8 +432,272 @@ impl Encoder<SnapMessage> for SnapCodec {
                 dst.put_u32_le(payload.len().try_into()?);
                 dst.put_slice(&payload);
             }
+            SnapKind::ClientInfo { info } => {
+                let payload = serde_json::to_vec(&info)?;
+                dst.put_u32_le(payload.len().try_into()?);
+                dst.put_slice(&payload);
+            }
+            SnapKind::Error { code, error, message } => {
+                dst.put_u32_le(code);
+                put_string(dst, &error)?;
+                put_string(dst, &message)?;
+            }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::{hash_map, vec};
+    use proptest::prelude::*;
+
+    fn codec() -> SnapCodec {
+        SnapCodec::new(Instant::now())
+    }
+
+    fn message(kind: SnapKind) -> SnapMessage {
+        SnapMessage {
+            base: SnapBase {
+                id: 7,
+                refers_to: 3,
+                received: Duration::zero(),
+                sent: 1.seconds() + 250.microseconds(),
+            },
+            kind,
+        }
+    }
+
+    fn encode(kind: SnapKind) -> BytesMut {
+        let mut buf = BytesMut::new();
+        codec().encode(message(kind), &mut buf).unwrap();
+        buf
+    }
+
+    /// A base message with the given type and size, followed by `data`
+    fn raw(msg_type: u16, size: u32, data: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u16_le(msg_type);
+        buf.put_slice(&[0; 20]);
+        buf.put_u32_le(size);
+        buf.put_slice(data);
+        buf
+    }
+
+    fn decode_error(mut buf: BytesMut) -> ProtocolError {
+        match codec().decode(&mut buf) {
+            Ok(msg) => panic!("Expected an error, got {:?}", msg),
+            Err(e) => e.downcast().unwrap(),
+        }
+    }
+
+    fn kinds() -> Vec<SnapKind> {
+        let mut tags = HashMap::new();
+        tags.insert("title".to_string(), serde_json::json!("Song"));
+        tags.insert("artist".to_string(), serde_json::json!(["A", "B"]));
+
+        vec![
+            SnapKind::CodecHeader {
+                codec: "flac".to_string(),
+                payload: Bytes::from_static(b"fLaC\0\0"),
+            },
+            SnapKind::WireChunk {
+                timestamp: 12.seconds() + 345_678.microseconds(),
+                payload: Bytes::from_static(&[1, 2, 3, 4]),
+            },
+            SnapKind::ServerSettings {
+                settings: SnapServerSettings {
+                    buffer_ms: 1000.milliseconds(),
+                    latency: 20.milliseconds(),
+                    muted: true,
+                    volume: 42,
+                },
+            },
+            SnapKind::Time {
+                delta: (-1).seconds() - 500.microseconds(),
+            },
+            SnapKind::Hello {
+                payload: SnapHello {
+                    arch: "armv6l".to_string(),
+                    client_name: "lights".to_string(),
+                    host_name: "pi".to_string(),
+                    id: "00:11:22:33:44:55".to_string(),
+                    instance: 1,
+                    mac: "00:11:22:33:44:55".to_string(),
+                    os: "linux".to_string(),
+                    protocol_version: 2,
+                    version: "0.1.0".to_string(),
+                },
+            },
+            SnapKind::StreamTags { tags },
+            SnapKind::ClientInfo {
+                info: SnapClientInfo { volume: 80, muted: false },
+            },
+            SnapKind::Error {
+                code: 401,
+                error: "Unauthorized".to_string(),
+                message: "Wrong password".to_string(),
+            },
+        ]
+    }
+
+    /// Durations with the full microsecond precision of the protocol
+    fn duration() -> impl Strategy<Value = Duration> {
+        (-1_000_000i64..1_000_000, 0i64..1_000_000).prop_map(|(s, us)| s.seconds() + us.microseconds())
+    }
+
+    fn bytes() -> impl Strategy<Value = Bytes> {
+        vec(any::<u8>(), 0..64).prop_map(Bytes::from)
+    }
+
+    fn kind() -> impl Strategy<Value = SnapKind> {
+        let text = "\\PC{0,16}";
+
+        let hello = (text, text, text, text, any::<usize>(), text, text, any::<usize>(), text).prop_map(
+            |(arch, client_name, host_name, id, instance, mac, os, protocol_version, version)| SnapHello {
+                arch,
+                client_name,
+                host_name,
+                id,
+                instance,
+                mac,
+                os,
+                protocol_version,
+                version,
+            },
+        );
+        let settings = (any::<i32>(), any::<i32>(), any::<bool>(), 0..=100usize).prop_map(
+            |(buffer_ms, latency, muted, volume)| SnapServerSettings {
+                buffer_ms: (buffer_ms as i64).milliseconds(),
+                latency: (latency as i64).milliseconds(),
+                muted,
+                volume,
+            },
+        );
+        let tags = hash_map(text, text.prop_map(serde_json::Value::from), 0..4);
+
+        prop_oneof![
+            (text, bytes()).prop_map(|(codec, payload)| SnapKind::CodecHeader { codec, payload }),
+            (duration(), bytes()).prop_map(|(timestamp, payload)| SnapKind::WireChunk { timestamp, payload }),
+            settings.prop_map(|settings| SnapKind::ServerSettings { settings }),
+            duration().prop_map(|delta| SnapKind::Time { delta }),
+            hello.prop_map(|payload| SnapKind::Hello { payload }),
+            tags.prop_map(|tags| SnapKind::StreamTags { tags }),
+            (0..=100usize, any::<bool>()).prop_map(|(volume, muted)| SnapKind::ClientInfo {
+                info: SnapClientInfo { volume, muted }
+            }),
+            (any::<u32>(), text, text).prop_map(|(code, error, message)| SnapKind::Error { code, error, message }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(kind in kind()) {
+            let mut buf = encode(kind.clone());
+            prop_assert_eq!(buf.len(), BASE_MESSAGE_SIZE + kind.size() as usize);
+
+            let msg = codec().decode(&mut buf).unwrap().unwrap();
+
+            prop_assert_eq!(msg.kind, kind);
+            prop_assert_eq!(msg.base.id, 7);
+            prop_assert_eq!(msg.base.refers_to, 3);
+            prop_assert_eq!(msg.base.sent, 1.seconds() + 250.microseconds());
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn garbage_never_panics(msg_type in 0u16..10, data in vec(any::<u8>(), 0..64)) {
+            // Whatever the body holds, the decoder gives back a message or an error
+            let _ = codec().decode(&mut raw(msg_type, data.len() as u32, &data));
+        }
+    }
+
+    #[test]
+    fn partial_messages_wait_for_more() {
+        let full = encode(kinds().remove(0));
+
+        for len in 0..full.len() {
+            let mut buf = BytesMut::from(&full[..len]);
+            assert!(codec().decode(&mut buf).unwrap().is_none());
+            assert_eq!(buf.len(), len);
+        }
+    }
+
+    #[test]
+    fn skips_unknown_messages() {
+        let mut buf = BytesMut::new();
+        for _ in 0..1000 {
+            buf.extend_from_slice(&raw(99, 3, &[1, 2, 3]));
+        }
+        buf.extend_from_slice(&encode(kinds().remove(3)));
+
+        let msg = codec().decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(msg.kind, kinds().remove(3));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn ignores_trailing_bytes() {
+        for kind in kinds() {
+            let mut buf = encode(kind.clone());
+
+            // Two more bytes of fields we don't know about
+            let size = u32::from_le_bytes(buf[22..26].try_into().unwrap()) + 2;
+            buf[22..26].copy_from_slice(&size.to_le_bytes());
+            buf.put_slice(&[0, 0]);
+            buf.extend_from_slice(&encode(kinds().remove(3)));
+
+            assert_eq!(codec().decode(&mut buf).unwrap().unwrap().kind, kind);
+            assert_eq!(codec().decode(&mut buf).unwrap().unwrap().kind, kinds().remove(3));
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn too_large() {
+        let size = MAX_MESSAGE_SIZE as u32 + 1;
+
+        match decode_error(raw(2, size, &[])) {
+            ProtocolError::TooLarge { size: s } => assert_eq!(s, size as usize),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let cases: Vec<(u16, Vec<u8>, &str)> = vec![
+            // The codec claims to be longer than the message
+            (1, vec![10, 0, 0, 0, b'f'], "codec"),
+            (1, vec![1, 0, 0, 0, b'f', 5, 0, 0, 0], "codec header payload"),
+            (2, vec![0, 0, 0, 0], "wire chunk timestamp"),
+            (2, vec![0; 8], "wire chunk payload"),
+            (3, vec![2, 0], "server settings"),
+            (4, vec![0; 7], "time delta"),
+            (8, vec![0; 3], "error code"),
+            (8, vec![0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0], "error message"),
+        ];
+
+        for (msg_type, data, field) in cases {
+            match decode_error(raw(msg_type, data.len() as u32, &data)) {
+                ProtocolError::Truncated { field: f } => assert_eq!(f, field),
+                e => panic!("Unexpected error {:?} for {}", e, field),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_strings_and_json() {
+        match decode_error(raw(1, 6, &[2, 0, 0, 0, 0xff, 0xfe])) {
+            ProtocolError::InvalidUtf8 { field } => assert_eq!(field, "codec"),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        match decode_error(raw(7, 6, &[2, 0, 0, 0, b'{', b'x'])) {
+            ProtocolError::InvalidJson { field, .. } => assert_eq!(field, "client info"),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+}
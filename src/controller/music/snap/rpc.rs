@@ -0,0 +1,288 @@
+use anyhow::{anyhow, Context, Result};
+use arc_swap::ArcSwap;
+use futures::{sink::SinkExt, stream::StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+
+/// A full status with lots of clients runs to tens of kilobytes, this leaves plenty of room while
+/// still stopping a broken server from filling our memory with one line
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+/// What the server says the stream is doing
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamStatus {
+    Playing,
+    /// The source isn't sending any audio, e.g. because it's paused or stopped
+    Idle,
+    Disabled,
+    #[serde(other)]
+    Unknown,
+}
+
+/// What we know about the stream our client is attached to
+#[derive(Debug, Clone, Default)]
+pub struct StreamState {
+    pub stream_id: Option<String>,
+    /// Unset while we aren't connected to the JSON-RPC API, or it doesn't know about our stream
+    pub status: Option<StreamStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Server {
+    groups: Vec<Group>,
+    streams: Vec<Stream>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Group {
+    id: String,
+    stream_id: String,
+    clients: Vec<Client>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Client {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Stream {
+    id: String,
+    status: StreamStatus,
+}
+
+/// Everything the server sends is either a response to a request or a notification
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Incoming {
+    Response {
+        id: u64,
+        #[serde(default)]
+        result: Option<Value>,
+        #[serde(default)]
+        error: Option<Value>,
+    },
+    Notification {
+        method: String,
+        params: Value,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct ServerUpdate {
+    server: Server,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamUpdate {
+    id: String,
+    stream: Stream,
+}
+
+#[derive(Debug, Deserialize)]
+struct GroupStreamChanged {
+    id: String,
+    stream_id: String,
+}
+
+/// Our copy of the parts of the server status we care about
+struct RpcClient {
+    /// The ID the server knows our audio client by
+    client_id: String,
+    /// The stream each group is playing and the clients in it, by group ID
+    groups: HashMap<String, (String, Vec<String>)>,
+    streams: HashMap<String, StreamStatus>,
+    output: Arc<ArcSwap<StreamState>>,
+}
+
+/// Follow the server's notifications, keeping `output` up to date with the stream `client_id` plays
+pub async fn run(addr: SocketAddr, client_id: String, output: Arc<ArcSwap<StreamState>>) -> Result<()> {
+    let stream = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("Error connecting to the JSON-RPC API at {}", addr))?;
+    let mut lines = Framed::new(stream, LinesCodec::new_with_max_length(MAX_LINE_LENGTH));
+
+    log::info!("Connected to the JSON-RPC API at {}", addr);
+
+    let mut client = RpcClient {
+        client_id,
+        groups: HashMap::new(),
+        streams: HashMap::new(),
+        output,
+    };
+
+    let request = json!({ "id": 1, "jsonrpc": "2.0", "method": "Server.GetStatus" });
+    lines.send(request.to_string()).await?;
+
+    let result = async {
+        while let Some(line) = lines.next().await {
+            let line = match line {
+                Ok(line) => line,
+                Err(LinesCodecError::MaxLineLengthExceeded) => {
+                    return Err(anyhow!("JSON-RPC message longer than {} bytes", MAX_LINE_LENGTH))
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            match serde_json::from_str::<Incoming>(&line) {
+                Ok(incoming) => client.process(incoming)?,
+                Err(e) => log::warn!("Ignoring unrecognized JSON-RPC message: {}", e),
+            }
+        }
+
+        Err(anyhow!("JSON-RPC connection closed"))
+    }
+    .await;
+
+    // We don't know anything anymore
+    client.output.store(Arc::new(StreamState::default()));
+
+    result
+}
+
+impl RpcClient {
+    fn process(&mut self, incoming: Incoming) -> Result<()> {
+        match incoming {
+            Incoming::Response { result: Some(result), .. } => {
+                let server: ServerUpdate = serde_json::from_value(result).context("Error parsing server status")?;
+                self.update_server(server.server);
+            }
+            Incoming::Response { id, error, .. } => {
+                return Err(anyhow!("JSON-RPC request {} failed: {:?}", id, error));
+            }
+            Incoming::Notification { method, params } => match method.as_str() {
+                "Server.OnUpdate" => {
+                    let update: ServerUpdate = serde_json::from_value(params)?;
+                    self.update_server(update.server);
+                }
+                "Stream.OnUpdate" => {
+                    let update: StreamUpdate = serde_json::from_value(params)?;
+                    self.streams.insert(update.id, update.stream.status);
+                }
+                "Group.OnStreamChanged" => {
+                    let update: GroupStreamChanged = serde_json::from_value(params)?;
+                    if let Some(group) = self.groups.get_mut(&update.id) {
+                        group.0 = update.stream_id;
+                    }
+                }
+                method => {
+                    log::trace!("Ignoring JSON-RPC notification {}", method);
+                    return Ok(());
+                }
+            },
+        }
+
+        self.publish();
+
+        Ok(())
+    }
+
+    fn update_server(&mut self, server: Server) {
+        self.groups = server
+            .groups
+            .into_iter()
+            .map(|group| {
+                let clients = group.clients.into_iter().map(|client| client.id).collect();
+                (group.id, (group.stream_id, clients))
+            })
+            .collect();
+
+        self.streams = server.streams.into_iter().map(|stream| (stream.id, stream.status)).collect();
+    }
+
+    fn publish(&self) {
+        let stream_id = self
+            .groups
+            .values()
+            .find(|(_, clients)| clients.contains(&self.client_id))
+            .map(|(stream_id, _)| stream_id.clone());
+
+        // Not knowing the status is no better than having no JSON-RPC at all, so leave it unset and
+        // let the controller watch the frames instead
+        let state = StreamState {
+            status: stream_id
+                .as_ref()
+                .and_then(|id| self.streams.get(id).copied())
+                .filter(|status| *status != StreamStatus::Unknown),
+            stream_id,
+        };
+
+        let output = self.output.load();
+
+        if output.stream_id != state.stream_id || output.status != state.status {
+            log::info!("Now attached to stream {:?}, which is {:?}", state.stream_id, state.status);
+        }
+
+        self.output.store(Arc::new(state));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(client_id: &str) -> RpcClient {
+        RpcClient {
+            client_id: client_id.to_string(),
+            groups: HashMap::new(),
+            streams: HashMap::new(),
+            output: Arc::new(ArcSwap::from_pointee(StreamState::default())),
+        }
+    }
+
+    fn status(stream_status: &str) -> Incoming {
+        serde_json::from_value(json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "result": {
+                "server": {
+                    "groups": [{ "id": "group", "stream_id": "default", "clients": [{ "id": "ours" }] }],
+                    "streams": [{ "id": "default", "status": stream_status }],
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn follows_our_stream() {
+        let mut client = client("ours");
+
+        client.process(status("playing")).unwrap();
+        assert_eq!(client.output.load().status, Some(StreamStatus::Playing));
+        assert_eq!(client.output.load().stream_id.as_deref(), Some("default"));
+
+        let update = json!({
+            "jsonrpc": "2.0",
+            "method": "Stream.OnUpdate",
+            "params": { "id": "default", "stream": { "id": "default", "status": "idle" } },
+        });
+        client.process(serde_json::from_value(update).unwrap()).unwrap();
+        assert_eq!(client.output.load().status, Some(StreamStatus::Idle));
+    }
+
+    #[test]
+    fn unknown_status_is_unset() {
+        let mut client = client("ours");
+
+        client.process(status("buffering")).unwrap();
+
+        assert_eq!(client.output.load().status, None);
+    }
+
+    #[test]
+    fn not_in_a_group_is_unset() {
+        let mut client = client("someone else");
+
+        client.process(status("playing")).unwrap();
+
+        assert_eq!(client.output.load().status, None);
+        assert_eq!(client.output.load().stream_id, None);
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::{anyhow, Context, Result};
+use arc_swap::ArcSwap;
+use futures::future::{BoxFuture, FutureExt};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+use crate::controller::music::snap::client::{SnapClient, SnapConfig};
+use crate::controller::music::snap::rpc::{self, StreamState};
+use crate::controller::music::source::{AudioSource, SnapState};
+use crate::controller::music::track::{PaletteConfig, Track};
+use crate::controller::music::Frame;
+
+/// Audio from a snapserver, along with the stream status and the tracks that it plays
+pub struct SnapSource {
+    client: SnapClient,
+    /// Follows the status of our stream, stopped when we disconnect
+    rpc: Option<JoinHandle<()>>,
+    state: SnapState,
+    palette: PaletteConfig,
+}
+
+impl SnapSource {
+    pub async fn connect(config: &SnapConfig, palette: PaletteConfig, state: SnapState) -> Result<SnapSource> {
+        log::info!("Connecting to SnapServer");
+
+        let client = SnapClient::start(config).await.context("Error connecting to SnapServer")?;
+
+        log::info!("Successfully connected to SnapServer");
+
+        let rpc = start_rpc(&client, config, &state.stream_state);
+
+        Ok(SnapSource {
+            client,
+            rpc,
+            state,
+            palette,
+        })
+    }
+
+    async fn next_frame(&mut self) -> Result<Option<Frame>> {
+        let frame = self
+            .client
+            .next()
+            .await
+            .context("Error retrieving packet from snapclient")?
+            .ok_or_else(|| anyhow!("SnapClient connection unexpectedly closed"))?;
+
+        log::trace!("Received frame from SnapServer");
+
+        if let Some(tags) = self.client.take_tags() {
+            // The album art is far too big to pass along
+            let mut shown = tags.clone();
+            shown.remove("artData");
+            *self.state.now_playing.lock().await = Some(shown);
+
+            let tracks = self.state.tracks.clone();
+            let seq = self.state.track_seq.fetch_add(1, Ordering::SeqCst) + 1;
+            let palette = self.palette.clone();
+
+            // Loading the album art takes a while, so keep it away from the frames. The controller
+            // goes by the number, so a track that took longer than the next one doesn't replace it.
+            tokio::task::spawn_blocking(move || {
+                // The controller is gone when this fails, and nobody needs the track anymore
+                let _ = tracks.send((seq, Track::new(tags, &palette)));
+            });
+        }
+
+        Ok(Some(frame))
+    }
+}
+
+impl AudioSource for SnapSource {
+    fn name(&self) -> String {
+        self.client.server_addr().map_or_else(|_| "unknown".to_string(), |addr| addr.to_string())
+    }
+
+    fn next(&mut self) -> BoxFuture<'_, Result<Option<Frame>>> {
+        self.next_frame().boxed()
+    }
+}
+
+impl Drop for SnapSource {
+    fn drop(&mut self) {
+        if let Some(rpc) = &self.rpc {
+            rpc.abort();
+        }
+
+        // We don't know anything about the stream without a connection
+        self.state.stream_state.store(Arc::new(StreamState::default()));
+
+        let now_playing = self.state.now_playing.clone();
+        tokio::spawn(async move {
+            *now_playing.lock().await = None;
+        });
+    }
+}
+
+/// Follow the status of our stream using the JSON-RPC API of the server we're connected to
+fn start_rpc(client: &SnapClient, config: &SnapConfig, stream_state: &Arc<ArcSwap<StreamState>>) -> Option<JoinHandle<()>> {
+    let port = config.rpc_port?;
+
+    let addr = match client.server_addr() {
+        Ok(addr) => SocketAddr::new(addr.ip(), port),
+        Err(e) => {
+            log::warn!("Unable to get the SnapServer address for JSON-RPC: {}", e);
+            return None;
+        }
+    };
+
+    let client_id = client.id().to_string();
+    let stream_state = stream_state.clone();
+
+    Some(tokio::spawn(async move {
+        if let Err(e) = rpc::run(addr, client_id, stream_state).await {
+            log::warn!("Stopped following the stream status, falling back to watching frames: {}", e);
+        }
+    }))
+}
@@ -0,0 +1,89 @@
+//! Where the music controller gets its audio from. Each kind of input is an `AudioSource`, so the
+//! analysis doesn't care whether the samples come from a snapserver, a file or a sound card.
+
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::AtomicUsize;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[cfg(feature = "alsa")]
+use crate::controller::music::alsa::{AlsaConfig, AlsaSource};
+use crate::controller::music::file::{FileConfig, FileSource};
+use crate::controller::music::pcm::{PcmConfig, PcmSource};
+use crate::controller::music::snap::client::SnapConfig;
+use crate::controller::music::snap::rpc::StreamState;
+use crate::controller::music::snap::source::SnapSource;
+use crate::controller::music::track::{PaletteConfig, Track};
+use crate::controller::music::Frame;
+
+/// Where the audio comes from
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SourceConfig {
+    /// Stream from a snapserver, set up under `snap`
+    Snapcast,
+    /// Play a local file
+    File(FileConfig),
+    /// Read raw samples that another program writes
+    Pcm(PcmConfig),
+    /// Capture from a sound card, when built with the `alsa` feature
+    #[cfg(feature = "alsa")]
+    Alsa(AlsaConfig),
+}
+
+/// A stream of blocks of samples
+pub trait AudioSource: Send {
+    /// What we're listening to, for the logs and the control API
+    fn name(&self) -> String;
+
+    /// The next block of samples, `None` once there's nothing left to play
+    fn next(&mut self) -> BoxFuture<'_, Result<Option<Frame>>>;
+}
+
+/// Something that trying again won't fix, like the server rejecting us
+#[derive(Debug)]
+pub struct FatalError(pub String);
+
+impl fmt::Display for FatalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for FatalError {}
+
+/// What a snapserver can tell us besides the audio
+#[derive(Clone)]
+pub struct SnapState {
+    pub stream_state: Arc<ArcSwap<StreamState>>,
+    /// Each track once its album art has loaded, along with its number from `track_seq`
+    pub tracks: Sender<(usize, Track)>,
+    /// Counts the tag updates, so a track whose album art took a while to load doesn't replace
+    /// one that came after it
+    pub track_seq: Arc<AtomicUsize>,
+    /// The tags of the track that's playing, for the control API
+    pub now_playing: Arc<Mutex<Option<HashMap<String, Value>>>>,
+}
+
+/// Connect to or open the configured source
+pub async fn open(
+    source: &SourceConfig,
+    snap: &SnapConfig,
+    palette: &PaletteConfig,
+    state: &SnapState,
+) -> Result<Box<dyn AudioSource>> {
+    Ok(match source {
+        SourceConfig::Snapcast => Box::new(SnapSource::connect(snap, palette.clone(), state.clone()).await?),
+        SourceConfig::File(config) => Box::new(FileSource::open(config.clone())?),
+        SourceConfig::Pcm(config) => Box::new(PcmSource::open(config.clone()).await?),
+        #[cfg(feature = "alsa")]
+        SourceConfig::Alsa(config) => Box::new(AlsaSource::open(config.clone()).await?),
+    })
+}
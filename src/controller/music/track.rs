@@ -0,0 +1,214 @@
+use anyhow::{anyhow, Context, Result};
+use image::DynamicImage;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::color::cmap::Colormap;
+use crate::color::palette;
+
+/// The names of the images we look for next to the file that's playing
+const COVER_NAMES: [&str; 4] = ["cover.jpg", "cover.png", "folder.jpg", "folder.png"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PaletteConfig {
+    /// Replace the colormaps with one made from each track's album art
+    pub enabled: bool,
+    /// The number of colors taken from the album art
+    pub size: usize,
+    /// Where the server's music library is, to find cover images next to the file that's playing
+    pub music_dir: Option<PathBuf>,
+}
+
+impl Default for PaletteConfig {
+    fn default() -> Self {
+        PaletteConfig {
+            enabled: false,
+            size: 4,
+            music_dir: None,
+        }
+    }
+}
+
+/// The song that's playing, from the stream's tags
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub tags: HashMap<String, Value>,
+    /// Made from the album art when the palette is enabled and we found the art
+    pub colormap: Option<Colormap>,
+}
+
+impl Track {
+    /// This loads the album art, so it takes a while
+    pub fn new(tags: HashMap<String, Value>, config: &PaletteConfig) -> Track {
+        let mut track = Track { tags, colormap: None };
+
+        if config.enabled {
+            match track.album_art(config) {
+                Ok(Some(art)) => {
+                    let colors = palette::from_image(&art, config.size.max(1));
+                    log::info!("Album art palette: {:?}", colors);
+
+                    track.colormap = Some(Colormap::from_palette(&colors));
+                }
+                Ok(None) => log::info!("No album art for this track"),
+                Err(e) => log::warn!("Error loading album art: {:#}", e),
+            }
+        }
+
+        track
+    }
+
+    /// A tag as text, lists like multiple artists are joined by commas
+    pub fn tag(&self, name: &str) -> Option<String> {
+        match self.tags.get(name)? {
+            Value::String(s) => Some(s.clone()),
+            Value::Array(values) => {
+                let values: Vec<&str> = values.iter().filter_map(Value::as_str).collect();
+                Some(values.join(", "))
+            }
+            Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn title(&self) -> Option<String> {
+        self.tag("title")
+    }
+
+    pub fn artist(&self) -> Option<String> {
+        self.tag("artist")
+    }
+
+    pub fn album(&self) -> Option<String> {
+        self.tag("album")
+    }
+
+    /// Look for art embedded in the tags, then a local art URL, then a cover next to the file
+    fn album_art(&self, config: &PaletteConfig) -> Result<Option<DynamicImage>> {
+        if let Some(Value::Object(art)) = self.tags.get("artData") {
+            let data = art
+                .get("data")
+                .and_then(Value::as_str)
+                .ok_or_else(|| anyhow!("Embedded album art is missing its data"))?;
+            let data = base64::decode(data).context("Error decoding embedded album art")?;
+
+            return Ok(Some(image::load_from_memory(&data).context("Error reading embedded album art")?));
+        }
+
+        if let Some(url) = self.tag("artUrl") {
+            let path = url.strip_prefix("file://").or_else(|| if url.starts_with('/') { Some(&url) } else { None });
+
+            match (path, &config.music_dir) {
+                (Some(path), Some(music_dir)) => {
+                    return load_image(&in_music_dir(music_dir, Path::new(path))?).map(Some);
+                }
+                (Some(_), None) => log::debug!("Ignoring {}, local album art has to be inside music_dir", url),
+                (None, _) => log::debug!("Only local album art is supported, ignoring {}", url),
+            }
+        }
+
+        if let (Some(music_dir), Some(file)) = (&config.music_dir, self.tag("file")) {
+            let dir = track_dir(music_dir, &file)?;
+
+            for name in COVER_NAMES.iter() {
+                let path = dir.join(name);
+
+                if path.is_file() {
+                    return load_image(&path).map(Some);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// `path` with any symlinks and ".." resolved, as long as that's inside `music_dir`. The tags come from
+/// the server, so don't let a path like "../../etc" or "/home" send us looking anywhere else.
+fn in_music_dir(music_dir: &Path, path: &Path) -> Result<PathBuf> {
+    let music_dir = music_dir
+        .canonicalize()
+        .with_context(|| format!("Error finding the music directory {}", music_dir.display()))?;
+
+    let resolved = music_dir
+        .join(path)
+        .canonicalize()
+        .with_context(|| format!("Error finding {}", path.display()))?;
+
+    if !resolved.starts_with(&music_dir) {
+        return Err(anyhow!("{} is outside the music directory", path.display()));
+    }
+
+    Ok(resolved)
+}
+
+/// The directory holding `file`, which has to be inside `music_dir`
+fn track_dir(music_dir: &Path, file: &str) -> Result<PathBuf> {
+    in_music_dir(music_dir, Path::new(file).parent().unwrap_or_else(|| Path::new("")))
+}
+
+fn load_image(path: &Path) -> Result<DynamicImage> {
+    image::open(path).with_context(|| format!("Error reading album art {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_dir_stays_in_music_dir() {
+        let root = std::env::temp_dir().join(format!("lights-track-{}", std::process::id()));
+        let music_dir = root.join("music");
+        std::fs::create_dir_all(music_dir.join("Artist/Album")).unwrap();
+
+        let music_dir_canonical = music_dir.canonicalize().unwrap();
+
+        assert_eq!(
+            track_dir(&music_dir, "Artist/Album/01 Song.flac").unwrap(),
+            music_dir_canonical.join("Artist/Album")
+        );
+        assert_eq!(track_dir(&music_dir, "Song.flac").unwrap(), music_dir_canonical);
+        assert!(track_dir(&music_dir, "../Song.flac").is_err());
+        assert!(track_dir(&music_dir, "Artist/../../Song.flac").is_err());
+        assert!(track_dir(&music_dir, "/tmp/Song.flac").is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn art_url_stays_in_music_dir() {
+        let root = std::env::temp_dir().join(format!("lights-art-{}", std::process::id()));
+        let music_dir = root.join("music");
+        std::fs::create_dir_all(&music_dir).unwrap();
+
+        let inside = music_dir.join("cover.png");
+        let outside = root.join("secret.png");
+        for path in [&inside, &outside].iter() {
+            image::RgbImage::new(2, 2).save(path).unwrap();
+        }
+
+        let track = |url: String| Track {
+            tags: vec![("artUrl".to_string(), Value::String(url))].into_iter().collect(),
+            colormap: None,
+        };
+        let config = PaletteConfig {
+            enabled: true,
+            music_dir: Some(music_dir.clone()),
+            ..PaletteConfig::default()
+        };
+
+        assert!(track(format!("file://{}", inside.display())).album_art(&config).unwrap().is_some());
+        assert!(track(inside.display().to_string()).album_art(&config).unwrap().is_some());
+        assert!(track(format!("file://{}", outside.display())).album_art(&config).is_err());
+        assert!(track(music_dir.join("../secret.png").display().to_string()).album_art(&config).is_err());
+
+        // Without a music directory there's nothing to check against
+        let config = PaletteConfig::default();
+        assert!(track(inside.display().to_string()).album_art(&config).unwrap().is_none());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,18 +1,20 @@
 use anyhow::Result;
 use druid::kurbo::PathEl;
 use rs_ws281x::{ChannelBuilder, ControllerBuilder, StripType};
+use serde::Deserialize;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 
 use std::convert::TryInto;
-use std::fs::File;
-use std::io::Write;
 
 
 use druid::widget::prelude::*;
-use druid::{AppLauncher, WindowDesc, Selector, Rect, WidgetExt, Target, Affine, RadialGradient};
+use druid::{AppLauncher, WindowDesc, Selector, Rect, Target, Affine};
 
 use crate::color::{NUM_LIGHTS, Color};
+use crate::color::calibration::{Calibration, CalibrationConfig, Profile};
+use crate::color::power::{PowerConfig, PowerLimiter};
+use crate::color::rgbw::{Rgbw, WhiteExtractor};
 
 const SET_COLOR: Selector<[(u8, druid::Color); NUM_LIGHTS]> = Selector::new("lights.set-color");
 
@@ -106,7 +108,113 @@ fn build_root_widget() -> impl Widget<LightState> {
     LightWidget
 }
 
-pub fn start(mut rx: mpsc::Receiver<[Color; NUM_LIGHTS]>) -> JoinHandle<Result<()>> {
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LightsConfig {
+    pub output: OutputConfig,
+}
+
+/// Where the colors end up
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputConfig {
+    /// A window on the desktop
+    Simulator {
+        #[serde(default)]
+        calibration: CalibrationConfig,
+    },
+    /// An LED strip hooked up to the Raspberry Pi
+    Ws281x {
+        #[serde(default = "default_pin")]
+        pin: i32,
+        #[serde(default = "default_leds_per_light")]
+        leds_per_light: usize,
+        #[serde(default)]
+        strip: Strip,
+        /// The color of the white LED on RGBW strips, see `WhiteExtractor`
+        #[serde(default = "default_white")]
+        white: [f64; 3],
+        #[serde(default)]
+        calibration: CalibrationConfig,
+        /// Limits the current drawn by the strip, unlimited when unset
+        #[serde(default)]
+        power: Option<PowerConfig>,
+    },
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig::Simulator { calibration: CalibrationConfig::default() }
+    }
+}
+
+fn default_pin() -> i32 {
+    18
+}
+
+fn default_leds_per_light() -> usize {
+    12
+}
+
+fn default_white() -> [f64; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+/// The kinds of LED strips we know how to drive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strip {
+    Ws2811Rgb,
+    Ws2811Grb,
+    #[default]
+    Ws2811Gbr,
+    Ws2812,
+    Sk6812,
+    Sk6812Rgbw,
+    Sk6812Grbw,
+}
+
+impl Strip {
+    fn strip_type(self) -> StripType {
+        match self {
+            Strip::Ws2811Rgb => StripType::Ws2811Rgb,
+            Strip::Ws2811Grb => StripType::Ws2811Grb,
+            Strip::Ws2811Gbr => StripType::Ws2811Gbr,
+            Strip::Ws2812 => StripType::Ws2812,
+            Strip::Sk6812 => StripType::Sk6812,
+            Strip::Sk6812Rgbw => StripType::Sk6812Rgbw,
+            Strip::Sk6812Grbw => StripType::Sk6812Grbw,
+        }
+    }
+
+    fn has_white(self) -> bool {
+        matches!(self, Strip::Sk6812Rgbw | Strip::Sk6812Grbw)
+    }
+
+    fn profile(self) -> Profile {
+        match self {
+            Strip::Ws2811Rgb | Strip::Ws2811Grb | Strip::Ws2811Gbr => Profile::Ws2811,
+            Strip::Ws2812 => Profile::Ws2812,
+            Strip::Sk6812 | Strip::Sk6812Rgbw | Strip::Sk6812Grbw => Profile::Sk6812,
+        }
+    }
+}
+
+pub fn start(config: LightsConfig, rx: mpsc::Receiver<[Color; NUM_LIGHTS]>) -> JoinHandle<Result<()>> {
+    match config.output {
+        OutputConfig::Simulator { calibration } => {
+            start_simulator(calibration.build(Profile::Display), rx)
+        }
+        OutputConfig::Ws281x { pin, leds_per_light, strip, white, calibration, power } => {
+            let calibration = calibration.build(strip.profile());
+            let extractor = if strip.has_white() { Some(WhiteExtractor::new(white)) } else { None };
+            let limiter = power.map(PowerLimiter::new);
+            start_ws281x(pin, leds_per_light, strip, calibration, extractor, limiter, rx)
+        }
+    }
+}
+
+fn start_simulator(calibration: Calibration, mut rx: mpsc::Receiver<[Color; NUM_LIGHTS]>) -> JoinHandle<Result<()>> {
     tokio::task::spawn_blocking(move || {
         let main_window = WindowDesc::new(build_root_widget).show_titlebar(false).title("Lights Visualization");
 
@@ -118,7 +226,10 @@ pub fn start(mut rx: mpsc::Receiver<[Color; NUM_LIGHTS]>) -> JoinHandle<Result<(
             let mut druid_colors = vec![(0, druid::Color::BLACK); NUM_LIGHTS];
             while let Some(data) = rx.blocking_recv() {
                 for (index, color) in data.iter().enumerate() {
-                    druid_colors[index] = (color.i, druid::Color::rgb8(color.r, color.g, color.b));
+                    let color = calibration.apply(color.apply_intensity());
+                    // Graph how bright the light actually is
+                    let brightness = color.r.max(color.g).max(color.b);
+                    druid_colors[index] = (brightness, druid::Color::rgb8(color.r, color.g, color.b));
                 }
 
                 // Wack
@@ -139,64 +250,76 @@ pub fn start(mut rx: mpsc::Receiver<[Color; NUM_LIGHTS]>) -> JoinHandle<Result<(
             .expect("Failed to launch lights");
 
         Ok(())
+    })
+}
+
+fn start_ws281x(
+    pin: i32,
+    leds_per_light: usize,
+    strip: Strip,
+    calibration: Calibration,
+    extractor: Option<WhiteExtractor>,
+    mut limiter: Option<PowerLimiter>,
+    mut rx: mpsc::Receiver<[Color; NUM_LIGHTS]>,
+) -> JoinHandle<Result<()>> {
+    tokio::task::spawn_blocking(move || {
+        log::info!("Starting Lights");
+
+        // TODO we can't do this in some kind of setup function becase Controller doesn't implement Send...
+        let mut controller = match ControllerBuilder::new()
+            .channel(
+                0,
+                ChannelBuilder::new()
+                    .pin(pin)
+                    .count((leds_per_light * NUM_LIGHTS) as i32)
+                    .strip_type(strip.strip_type())
+                    .brightness(255)
+                    .build(),
+            )
+            .build() {
+                Ok(controller) => controller,
+                Err(e) => {
+                    log::error!("Failed to build controller: {}", e);
+                    return Err(e.into())
+                }
+            };
+
+        log::trace!("Entering main loop");
 
+        while let Some(colors) = rx.blocking_recv() {
+            log::trace!("Received colors {:?}", colors);
 
+            let mut leds = [Rgbw::default(); NUM_LIGHTS];
+            for (led, color) in leds.iter_mut().zip(colors.iter()) {
+                let color = calibration.apply(color.apply_intensity());
 
-        // log::info!("Starting Lights");
-
-        // // let mut bas = File::create("bas.txt").unwrap();
-        // // let mut mid = File::create("mid.txt").unwrap();
-        // // let mut tre = File::create("tre.txt").unwrap();
-
-        // // TODO we can't do this in some kind of setup function becase Controller doesn't implement Send...
-        // let mut controller = match ControllerBuilder::new()
-        //     .channel(
-        //         0,
-        //         ChannelBuilder::new()
-        //             .pin(18) // TODO based on some config
-        //             .count(12 * NUM_LIGHTS as i32)
-        //             .strip_type(StripType::Ws2811Gbr)
-        //             .brightness(255)
-        //             .build(),
-        //     )
-        //     .build() {
-        //         Ok(controller) => controller,
-        //         Err(e) => {
-        //             log::error!("Failed to build controller: {}", e);
-        //             return Err(e.into())
-        //         }
-        //     };
-
-        // log::trace!("Entering main loop");
-
-        // // let mut leds = [[0; 4]; 3];
-
-        // while let Some(color) = rx.blocking_recv() {
-        //     log::trace!("Received colors {:?}", color);
-
-        //     for (i, led) in controller.leds_mut(0).iter_mut().enumerate() {
-        //         if i / 12 == 0 {
-        //             *led = [color[0][0], color[0][1], color[0][2], 0];
-        //         } else if i / 12 == 1 {
-        //             *led = [color[1][0], color[1][1], color[1][2], 0];
-        //         } else {
-        //             *led = [color[2][0], color[2][1], color[2][2], 0];
-        //         }
-        //     }
-
-        //     // writeln!(bas, "{}", color[0]).unwrap();
-        //     // writeln!(mid, "{}", color[1]).unwrap();
-        //     // writeln!(tre, "{}", color[2]).unwrap();
-
-        //     // log::trace!("Sucessfully set color")
-        //     match controller.render() {
-        //         Ok(()) => log::trace!("Sucessfully set color"),
-        //         Err(e) => log::error!("Failed to set color: {}", e),
-        //     }
-        // }
-
-        // log::info!("Lights stopping");
-
-        // Ok(())
+                *led = match &extractor {
+                    Some(extractor) => extractor.extract(color),
+                    None => color.into(),
+                };
+            }
+
+            // The calibrated values are what actually draws current
+            if let Some(limiter) = limiter.as_mut() {
+                limiter.limit(&mut leds, leds_per_light);
+            }
+
+            for (i, led) in controller.leds_mut(0).iter_mut().enumerate() {
+                let color = leds[(i / leds_per_light).min(NUM_LIGHTS - 1)];
+
+                // ws2811_led_t is 0xWWRRGGBB, so a little endian RawColor is blue first. The strip
+                // type takes care of the order the strip wants the channels in.
+                *led = [color.b, color.g, color.r, color.w];
+            }
+
+            match controller.render() {
+                Ok(()) => log::trace!("Sucessfully set color"),
+                Err(e) => log::error!("Failed to set color: {}", e),
+            }
+        }
+
+        log::info!("Lights stopping");
+
+        Ok(())
     })
 }
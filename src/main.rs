@@ -1,13 +1,19 @@
-use std::time::{Duration, Instant, SystemTime};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use simple_logger::SimpleLogger;
 use tokio::runtime::Runtime;
 
+mod brightness;
 mod color;
+mod config;
+mod control;
 mod controller;
 mod lights;
 
+use brightness::Brightness;
+use config::{Config, DEFAULT_CONFIG_PATH};
 use controller::Controller;
 use controller::music::MusicController;
 use controller::blank::BlankController;
@@ -17,18 +23,23 @@ use tokio::sync::mpsc;
 fn main() -> Result<()> {
     SimpleLogger::new().with_level(log::LevelFilter::Debug).init().unwrap();
 
+    let config_path = std::env::args().nth(1).unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+    let config = Config::load(config_path)?;
+
     let rt = Runtime::new().unwrap();
 
     let _guard = rt.enter();
 
     let (lights_tx, lights_rx) = mpsc::channel(50);
-    let lights = lights::start(lights_rx);
+    let _lights = lights::start(config.lights.clone(), lights_rx);
 
-    let mut controllers = Vec::new();
+    // Applied on top of every controller
+    let brightness = Arc::new(Mutex::new(Brightness::new(config.brightness.clone())));
+    let music = setup_music(&config)?;
+    let _control = rt.block_on(control::start(config.control.clone(), brightness.clone(), music.status()))?;
 
-    // Added in priority order
-    controllers.push(("Music", setup_music()));
-    controllers.push(("Blank", setup_blank()));
+    // In priority order
+    let mut controllers: Vec<(&str, Box<dyn Controller>)> = vec![("Music", Box::new(music)), ("Blank", setup_blank())];
 
     let frame_duration = Duration::from_secs(1) / 60;
 
@@ -45,11 +56,11 @@ fn main() -> Result<()> {
         // Iterate in priority order
         for (index, (name, controller)) in controllers.iter_mut().enumerate() {
             if controller.is_active() {
-                if active_index.replace(index).map_or(true, |i| index != i) {
+                if active_index.replace(index).is_none_or(|i| index != i) {
                     log::info!("Controller {} just took over", name);
                 }
 
-                let color = controller.tick();
+                let color = brightness.lock().unwrap().apply(controller.tick());
 
                 lights_tx.blocking_send(color)?;
 
@@ -73,12 +84,10 @@ fn main() -> Result<()> {
             std::thread::sleep(frame_duration - frame_elapsed);
         }
     }
-
-    Ok(())
 }
 
-fn setup_music() -> Box<dyn Controller> {
-    Box::new(MusicController::start())
+fn setup_music(config: &Config) -> Result<MusicController> {
+    MusicController::start(config.music.clone())
 }
 
 fn setup_blank() -> Box<dyn Controller> {